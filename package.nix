@@ -5,14 +5,13 @@
   installShellFiles,
   makeBinaryWrapper,
   darwin,
-  nvd,
   use-nom ? true,
   nix-output-monitor ? null,
   rev ? "dirty",
 }:
 assert use-nom -> nix-output-monitor != null;
 let
-  runtimeDeps = [ nvd ] ++ lib.optionals use-nom [ nix-output-monitor ];
+  runtimeDeps = lib.optionals use-nom [ nix-output-monitor ];
   cargoToml = builtins.fromTOML (builtins.readFile ./Cargo.toml);
 in
 rustPlatform.buildRustPackage {
@@ -0,0 +1,161 @@
+use std::path::Path;
+
+use color_eyre::eyre::bail;
+use owo_colors::OwoColorize;
+use regex::Regex;
+use tracing::debug;
+
+use crate::commands::Command;
+use crate::Result;
+
+/// What `switch-to-configuration` would do when activating a configuration
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct DryActivation {
+    pub stop: Vec<String>,
+    pub not_stopped: Vec<String>,
+    pub restart: Vec<String>,
+    pub start: Vec<String>,
+    pub reload: Vec<String>,
+    pub restart_systemd: bool,
+    /// Output of the activation scripts' dry-activate hooks
+    pub activation: Vec<String>,
+}
+
+impl DryActivation {
+    /// Parse the output of `switch-to-configuration dry-activate`
+    pub fn parse(output: &str) -> Self {
+        let units_re =
+            Regex::new(r"^would (NOT )?(\w+) the following (?:[\w ]+ )?units?: (.*)$").unwrap();
+
+        let mut report = Self::default();
+
+        for line in output.lines().map(|line| line.trim_end_matches('\r')) {
+            if line.trim().is_empty() || line == "would activate the configuration..." {
+                continue;
+            }
+
+            if line == "would restart systemd" {
+                report.restart_systemd = true;
+                continue;
+            }
+
+            let Some(caps) = units_re.captures(line) else {
+                report.activation.push(line.to_owned());
+                continue;
+            };
+
+            let units = caps[3]
+                .split(',')
+                .map(|unit| unit.trim().to_owned())
+                .filter(|unit| !unit.is_empty());
+
+            let list = match (caps.get(1).is_some(), &caps[2]) {
+                (false, "stop") => &mut report.stop,
+                (true, "stop") => &mut report.not_stopped,
+                (false, "restart") => &mut report.restart,
+                (false, "start") => &mut report.start,
+                (false, "reload") => &mut report.reload,
+                _ => {
+                    report.activation.push(line.to_owned());
+                    continue;
+                }
+            };
+            list.extend(units);
+        }
+
+        report
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn print(&self) {
+        println!("{}", "Activation changes:".bold());
+
+        if self.is_empty() {
+            println!("No units would be changed");
+            return;
+        }
+
+        let sections = [
+            ("[S]".red().to_string(), "stop", &self.stop),
+            ("[R]".yellow().to_string(), "restart", &self.restart),
+            ("[L]".bright_cyan().to_string(), "reload", &self.reload),
+            ("[A]".green().to_string(), "start", &self.start),
+            (
+                "[K]".magenta().to_string(),
+                "keep running, changed",
+                &self.not_stopped,
+            ),
+        ];
+
+        for (tag, action, units) in sections {
+            if units.is_empty() {
+                continue;
+            }
+            println!("{tag} {}: {}", action, units.join(", "));
+        }
+
+        if self.restart_systemd {
+            println!("{} systemd would be restarted", "[R]".yellow());
+        }
+
+        if !self.activation.is_empty() {
+            println!("{}", "Activation script:".bold());
+            for line in &self.activation {
+                println!("  {line}");
+            }
+        }
+    }
+}
+
+/// Run `switch-to-configuration dry-activate` for a toplevel, locally or on `host`
+pub fn dry_activate(toplevel: &Path, elevate: bool, host: Option<String>) -> Result<DryActivation> {
+    let output = Command::new(toplevel.join("bin").join("switch-to-configuration"))
+        .arg("dry-activate")
+        .elevate(elevate)
+        .ssh(host.clone())
+        .merge_stderr(true)
+        .message("Checking what activation would change")
+        .run_capture()?;
+
+    let Some(output) = output else {
+        bail!("No output from switch-to-configuration dry-activate");
+    };
+
+    debug!(?output);
+
+    // The output is captured, so remote sudo runs with -n instead of prompting
+    if let Some(host) = host.filter(|_| elevate) {
+        if output.contains("sudo: a password is required") {
+            bail!("Dry activation on {host} needs passwordless sudo");
+        }
+    }
+
+    Ok(DryActivation::parse(&output))
+}
+
+#[test]
+fn test_parse_dry_activation() {
+    let output = "\
+would stop the following units: foo.service, bar.timer
+would NOT stop the following changed units: systemd-journald.service
+would activate the configuration...
+would create user alice
+would restart systemd
+would restart the following units: sshd.service
+would start the following units: postgresql.service
+would reload the following units: dbus.service\r
+";
+
+    let report = DryActivation::parse(output);
+
+    assert_eq!(report.stop, ["foo.service", "bar.timer"]);
+    assert_eq!(report.not_stopped, ["systemd-journald.service"]);
+    assert_eq!(report.restart, ["sshd.service"]);
+    assert_eq!(report.start, ["postgresql.service"]);
+    assert_eq!(report.reload, ["dbus.service"]);
+    assert!(report.restart_systemd);
+    assert_eq!(report.activation, ["would create user alice"]);
+}
@@ -0,0 +1,257 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Local, Utc};
+use color_eyre::eyre::Context;
+use owo_colors::OwoColorize;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::commands::{self, Command};
+use crate::installable::Installable;
+use crate::interface::LogArgs;
+use crate::Result;
+
+// Machine-wide changes, shared by every user of the machine
+const SYSTEM_LOG: &str = "/var/lib/nh/audit.jsonl";
+
+/// Which log an entry goes to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    /// System activations and cleanups, written through sudo when needed
+    System,
+    /// Changes to the user's own profiles, like home-manager
+    User,
+}
+
+/// One line of the audit log
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Entry {
+    pub timestamp: DateTime<Utc>,
+    pub user: String,
+    pub hostname: String,
+    /// Like `os`, `home`, `darwin` or `clean`
+    pub command: String,
+    /// Like `switch`, `boot` or `rollback`
+    pub variant: String,
+    pub installable: Option<String>,
+    pub flake_revision: Option<String>,
+    pub out_path: Option<PathBuf>,
+    pub generation: Option<u64>,
+    pub changes: Option<String>,
+    /// `success`, or the error that stopped the operation
+    pub outcome: String,
+
+    #[serde(skip)]
+    scope: Option<Scope>,
+}
+
+impl Entry {
+    pub fn new(command: &str, variant: &str) -> Self {
+        Self {
+            timestamp: Utc::now(),
+            user: current_user(),
+            hostname: hostname::get()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            command: command.to_owned(),
+            variant: variant.to_owned(),
+            installable: None,
+            flake_revision: None,
+            out_path: None,
+            generation: None,
+            changes: None,
+            outcome: String::new(),
+            scope: None,
+        }
+    }
+
+    /// Record the installable and the revision of its flake, if it has one
+    pub fn installable(&mut self, installable: &Installable) {
+        self.installable = Some(installable.to_args().join(" "));
+        if let Installable::Flake { reference, .. } = installable {
+            self.flake_revision = flake_revision(reference);
+        }
+    }
+
+    /// Mark the point where changes start being made, only those entries get written
+    pub fn start(&mut self, scope: Scope) {
+        self.scope = Some(scope);
+    }
+
+    /// Write the entry if the operation got to [`Entry::start`], logging errors instead of
+    /// failing the operation
+    pub fn finish<T>(mut self, result: &Result<T>) {
+        let Some(scope) = self.scope else {
+            return;
+        };
+
+        self.timestamp = Utc::now();
+        self.outcome = match result {
+            Ok(_) => String::from("success"),
+            Err(err) => format!("failed: {err}"),
+        };
+
+        if let Err(err) = self.write(scope) {
+            warn!("Failed to write the audit log: {err}");
+        }
+    }
+
+    fn write(&self, scope: Scope) -> Result<()> {
+        let line = serde_json::to_string(self)?;
+        debug!(?scope, %line, "Writing audit entry");
+
+        let path = log_file(scope)?;
+        if scope == Scope::System && !nix::unistd::Uid::effective().is_root() {
+            return commands::write_file(&path, &line, true, true, None);
+        }
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("Opening {}", path.display()))?;
+        writeln!(file, "{line}")?;
+        Ok(())
+    }
+}
+
+/// User that ran nh, looking through sudo
+fn current_user() -> String {
+    std::env::var("SUDO_USER")
+        .or_else(|_| std::env::var("USER"))
+        .ok()
+        .or_else(|| {
+            nix::unistd::User::from_uid(nix::unistd::Uid::current())
+                .ok()
+                .flatten()
+                .map(|user| user.name)
+        })
+        .unwrap_or_else(|| String::from("unknown"))
+}
+
+fn flake_revision(reference: &str) -> Option<String> {
+    let output = Command::new("nix")
+        .args(["flake", "metadata", "--json", reference])
+        .run_capture()
+        .ok()??;
+    let metadata: serde_json::Value = serde_json::from_str(&output).ok()?;
+
+    ["revision", "dirtyRevision"]
+        .iter()
+        .find_map(|key| metadata.get(key)?.as_str())
+        .or_else(|| metadata.get("locked")?.get("narHash")?.as_str())
+        .map(String::from)
+}
+
+fn log_file(scope: Scope) -> Result<PathBuf> {
+    match scope {
+        Scope::System => Ok(PathBuf::from(SYSTEM_LOG)),
+        Scope::User => {
+            let state_dir = match std::env::var_os("XDG_STATE_HOME") {
+                Some(dir) if Path::new(&dir).is_absolute() => PathBuf::from(dir),
+                _ => PathBuf::from(std::env::var("HOME").context("Getting home directory")?)
+                    .join(".local/state"),
+            };
+            Ok(state_dir.join("nh").join("audit.jsonl"))
+        }
+    }
+}
+
+fn read_log(path: &Path) -> Result<Vec<Entry>> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err).with_context(|| format!("Reading {}", path.display())),
+    };
+
+    Ok(contents
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .filter_map(|line| match serde_json::from_str(line) {
+            Ok(entry) => Some(entry),
+            Err(err) => {
+                warn!(
+                    "Skipping malformed audit entry in {}: {err}",
+                    path.display()
+                );
+                None
+            }
+        })
+        .collect())
+}
+
+impl LogArgs {
+    pub fn run(&self) -> Result<()> {
+        let mut entries = read_log(&log_file(Scope::System)?)?;
+        entries.extend(read_log(&log_file(Scope::User)?)?);
+
+        let since = self
+            .since
+            .map(|since| Utc::now() - chrono::Duration::from_std(*since).unwrap_or_default());
+
+        entries.retain(|entry| {
+            self.host
+                .as_ref()
+                .is_none_or(|host| &entry.hostname == host)
+                && since.is_none_or(|since| entry.timestamp >= since)
+        });
+        entries.sort_by_key(|entry| entry.timestamp);
+
+        if self.json {
+            for entry in &entries {
+                println!("{}", serde_json::to_string(entry)?);
+            }
+            return Ok(());
+        }
+
+        for entry in &entries {
+            let outcome = if entry.outcome == "success" {
+                entry.outcome.green().to_string()
+            } else {
+                entry.outcome.red().to_string()
+            };
+
+            println!(
+                "{} {} {} {} {}{} {}",
+                entry
+                    .timestamp
+                    .with_timezone(&Local)
+                    .format("%Y-%m-%d %H:%M:%S"),
+                entry.user.bold(),
+                entry.hostname,
+                entry.command,
+                entry.variant,
+                entry
+                    .generation
+                    .map(|generation| format!(" (generation {generation})"))
+                    .unwrap_or_default(),
+                outcome,
+            );
+
+            if let Some(changes) = &entry.changes {
+                println!("    {changes}");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[test]
+fn test_entry_roundtrip() {
+    let mut entry = Entry::new("os", "switch");
+    entry.generation = Some(42);
+    entry.outcome = String::from("success");
+
+    let line = serde_json::to_string(&entry).unwrap();
+    let parsed: Entry = serde_json::from_str(&line).unwrap();
+
+    assert_eq!(parsed.command, "os");
+    assert_eq!(parsed.generation, Some(42));
+    assert_eq!(parsed.scope, None);
+}
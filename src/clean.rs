@@ -15,7 +15,7 @@ use regex::Regex;
 use tracing::{debug, info, instrument, span, warn, Level};
 use uzers::os::unix::UserExt;
 
-use crate::{commands::Command, *};
+use crate::{audit, commands::Command, generations, pins, *};
 
 // Nix impl:
 // https://github.com/NixOS/nix/blob/master/src/nix-collect-garbage/nix-collect-garbage.cc
@@ -25,6 +25,7 @@ struct Generation {
     number: u32,
     last_modified: SystemTime,
     path: PathBuf,
+    pin: Option<pins::Pin>,
 }
 
 type ToBeRemoved = bool;
@@ -52,6 +53,11 @@ impl interface::CleanMode {
                     crate::self_elevate();
                 }
                 profiles.extend(profiles_in_dir("/nix/var/nix/profiles"));
+                // Profiles created with --profile-name, only present if any were
+                let system_profiles = Path::new(generations::SYSTEM_PROFILES_DIR);
+                if system_profiles.is_dir() {
+                    profiles.extend(profiles_in_dir(system_profiles));
+                }
                 for read_dir in PathBuf::from("/nix/var/nix/profiles/per-user").read_dir()? {
                     let path = read_dir?.path();
                     profiles.extend(profiles_in_dir(path));
@@ -168,10 +174,7 @@ impl interface::CleanMode {
         println!("Keeping {} generation(s)", args.keep.green());
         println!("Keeping paths newer than {}", args.keep_since.green());
         println!();
-        println!("legend:");
-        println!("{}: path to be kept", "OK".green());
-        println!("{}: path to be removed", "DEL".red());
-        println!();
+        print_legend();
         if !gcroots_tagged.is_empty() {
             println!(
                 "{}",
@@ -192,15 +195,7 @@ impl interface::CleanMode {
             println!();
         }
         for (profile, generations_tagged) in profiles_tagged.iter() {
-            println!("{}", profile.to_string_lossy().blue().bold());
-            for (gen, tbr) in generations_tagged.iter().rev() {
-                if *tbr {
-                    println!("- {} {}", "DEL".red(), gen.path.to_string_lossy());
-                } else {
-                    println!("- {} {}", "OK ".green(), gen.path.to_string_lossy());
-                };
-            }
-            println!();
+            print_generations_tagged(profile, generations_tagged);
         }
 
         // Clean the paths
@@ -211,7 +206,32 @@ impl interface::CleanMode {
             }
         }
 
+        let (variant, scope) = match self {
+            // Only shared profiles go to the system log, a user's own ones don't need sudo
+            interface::CleanMode::Profile(args)
+                if args.profile.starts_with("/nix/var/nix/profiles")
+                    && !args.profile.starts_with("/nix/var/nix/profiles/per-user") =>
+            {
+                ("profile", audit::Scope::System)
+            }
+            interface::CleanMode::Profile(_) => ("profile", audit::Scope::User),
+            interface::CleanMode::All(_) => ("all", audit::Scope::System),
+            interface::CleanMode::User(_) => ("user", audit::Scope::User),
+        };
+        let mut audit = audit::Entry::new("clean", variant);
+
         if !args.dry {
+            let generations = profiles_tagged
+                .values()
+                .flat_map(|generations_tagged| generations_tagged.values())
+                .filter(|tbr| **tbr)
+                .count();
+            let gcroots = gcroots_tagged.values().filter(|tbr| **tbr).count();
+            audit.changes = Some(format!(
+                "removed {generations} generations, {gcroots} gcroots"
+            ));
+            audit.start(scope);
+
             for (path, tbr) in &gcroots_tagged {
                 if *tbr {
                     remove_path_nofail(path);
@@ -227,11 +247,94 @@ impl interface::CleanMode {
             }
         }
 
-        if !args.nogc {
+        let result = if args.nogc {
+            Ok(())
+        } else {
             Command::new("nix")
                 .args(["store", "gc"])
                 .dry(args.dry)
                 .message("Performing garbage collection on the nix store")
+                .run()
+        };
+        audit.finish(&result);
+        result
+    }
+}
+
+impl interface::OsDeleteArgs {
+    pub fn run(&self) -> Result<()> {
+        let selectors = self
+            .generations
+            .iter()
+            .map(|s| s.parse::<GenerationSelector>())
+            .collect::<Result<Vec<_>>>()?;
+        debug!(?selectors);
+
+        if selectors.iter().all(|s| s.exclude) {
+            bail!("Only exclusions were given, select the generations to delete too, like `.. !42` for all but 42");
+        }
+
+        if !nix::unistd::Uid::effective().is_root() && !self.dry {
+            crate::self_elevate();
+        }
+
+        let current = generations::current(&self.profile)?;
+        let booted = Path::new(generations::BOOTED_SYSTEM).canonicalize().ok();
+
+        let mut generations_tagged = profile_generations(&self.profile)?;
+        for (gen, tbr) in generations_tagged.iter_mut() {
+            let is_current = u64::from(gen.number) == current;
+            let is_booted = booted.is_some() && gen.path.canonicalize().ok() == booted;
+
+            let matches = |selector: &GenerationSelector| match selector.target {
+                SelectorTarget::Range(from, to) => {
+                    from.is_none_or(|from| gen.number >= from)
+                        && to.is_none_or(|to| gen.number <= to)
+                }
+                SelectorTarget::Current => is_current,
+                SelectorTarget::Booted => is_booted,
+            };
+
+            let selected = selectors.iter().any(|s| !s.exclude && matches(s));
+            let excluded = selectors.iter().any(|s| s.exclude && matches(s));
+
+            *tbr = selected && !excluded && !is_current && !is_booted && gen.pin.is_none();
+        }
+
+        if !generations_tagged.values().any(|tbr| *tbr) {
+            bail!(
+                "No generations to delete, current, booted and pinned generations are always kept"
+            );
+        }
+
+        use owo_colors::OwoColorize;
+        println!();
+        println!("{}", "Welcome to nh os delete".bold());
+        println!();
+        print_legend();
+        print_generations_tagged(&self.profile, &generations_tagged);
+
+        if self.ask {
+            info!("Confirm the deletion plan?");
+            if !dialoguer::Confirm::new().default(false).interact()? {
+                bail!("User rejected the deletion plan");
+            }
+        }
+
+        if self.dry {
+            return Ok(());
+        }
+
+        for (gen, tbr) in generations_tagged.iter().rev() {
+            if *tbr {
+                remove_path_nofail(&gen.path);
+            }
+        }
+
+        if self.boot {
+            Command::new(self.profile.join("bin").join("switch-to-configuration"))
+                .arg("boot")
+                .message("Refreshing bootloader entries")
                 .run()?;
         }
 
@@ -239,10 +342,183 @@ impl interface::CleanMode {
     }
 }
 
+impl interface::OsPinArgs {
+    pub fn run(&self) -> Result<()> {
+        let exists = generations::list(&self.profile)?
+            .iter()
+            .any(|(number, _)| *number == self.generation);
+        if !exists {
+            bail!(
+                "Generation {} doesn't exist in {}",
+                self.generation,
+                self.profile.display()
+            );
+        }
+
+        if !nix::unistd::Uid::effective().is_root() && needs_root(&self.profile) {
+            crate::self_elevate();
+        }
+
+        let mut profile_pins = pins::read(&self.profile)?;
+        profile_pins.insert(
+            self.generation,
+            pins::Pin {
+                label: self.label.clone(),
+            },
+        );
+        pins::write(&self.profile, profile_pins)?;
+
+        info!("Pinned generation {}", self.generation);
+        Ok(())
+    }
+}
+
+impl interface::OsUnpinArgs {
+    pub fn run(&self) -> Result<()> {
+        let mut profile_pins = pins::read(&self.profile)?;
+        if !profile_pins.contains_key(&self.generation) {
+            bail!("Generation {} is not pinned", self.generation);
+        }
+
+        if !nix::unistd::Uid::effective().is_root() && needs_root(&self.profile) {
+            crate::self_elevate();
+        }
+
+        profile_pins.remove(&self.generation);
+        pins::write(&self.profile, profile_pins)?;
+
+        info!("Unpinned generation {}", self.generation);
+        Ok(())
+    }
+}
+
+/// Whether the profile's directory is not writable by the current user
+fn needs_root(profile: &Path) -> bool {
+    let dir = profile.parent().unwrap_or_else(|| Path::new("."));
+    faccessat(None, dir, AccessFlags::W_OK, AtFlags::empty()).is_err()
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum SelectorTarget {
+    /// Inclusive range, open on the missing ends
+    Range(Option<u32>, Option<u32>),
+    Current,
+    Booted,
+}
+
+/// One of `N`, `A..B`, `A..`, `..B`, `current` or `booted`, optionally prefixed with `!` to exclude it
+#[derive(Debug, PartialEq, Eq)]
+struct GenerationSelector {
+    exclude: bool,
+    target: SelectorTarget,
+}
+
+impl std::str::FromStr for GenerationSelector {
+    type Err = color_eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (exclude, rest) = match s.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, s),
+        };
+
+        let parse_bound = |bound: &str| -> Result<Option<u32>> {
+            if bound.is_empty() {
+                Ok(None)
+            } else {
+                bound
+                    .parse()
+                    .map(Some)
+                    .wrap_err_with(|| format!("Invalid generation number `{bound}` in `{s}`"))
+            }
+        };
+
+        let target = match rest {
+            "current" => SelectorTarget::Current,
+            "booted" => SelectorTarget::Booted,
+            _ => match rest.split_once("..") {
+                Some((from, to)) => SelectorTarget::Range(parse_bound(from)?, parse_bound(to)?),
+                None => {
+                    let number = parse_bound(rest)?
+                        .ok_or_else(|| eyre!("Empty generation selector `{s}`"))?;
+                    SelectorTarget::Range(Some(number), Some(number))
+                }
+            },
+        };
+
+        Ok(Self { exclude, target })
+    }
+}
+
+#[test]
+fn test_generation_selector() {
+    let parse = |s: &str| s.parse::<GenerationSelector>().unwrap();
+
+    assert_eq!(
+        parse("12"),
+        GenerationSelector {
+            exclude: false,
+            target: SelectorTarget::Range(Some(12), Some(12))
+        }
+    );
+    assert_eq!(
+        parse("120..125").target,
+        SelectorTarget::Range(Some(120), Some(125))
+    );
+    assert_eq!(parse("..5").target, SelectorTarget::Range(None, Some(5)));
+    assert_eq!(parse("!7..").target, SelectorTarget::Range(Some(7), None));
+    assert!(parse("!7..").exclude);
+    assert_eq!(
+        parse("!current"),
+        GenerationSelector {
+            exclude: true,
+            target: SelectorTarget::Current
+        }
+    );
+    assert_eq!(parse("booted").target, SelectorTarget::Booted);
+    assert!("foo".parse::<GenerationSelector>().is_err());
+    assert!("!".parse::<GenerationSelector>().is_err());
+}
+
+fn print_legend() {
+    use owo_colors::OwoColorize;
+    println!("legend:");
+    println!("{}: path to be kept", "OK".green());
+    println!("{}: pinned generation, always kept", "PIN".cyan());
+    println!("{}: path to be removed", "DEL".red());
+    println!();
+}
+
+fn print_generations_tagged(profile: &Path, generations_tagged: &GenerationsTagged) {
+    use owo_colors::OwoColorize;
+    println!("{}", profile.to_string_lossy().blue().bold());
+    for (gen, tbr) in generations_tagged.iter().rev() {
+        if *tbr {
+            println!("- {} {}", "DEL".red(), gen.path.to_string_lossy());
+        } else if let Some(pin) = &gen.pin {
+            match &pin.label {
+                Some(label) => {
+                    println!(
+                        "- {} {} ({})",
+                        "PIN".cyan(),
+                        gen.path.to_string_lossy(),
+                        label
+                    )
+                }
+                None => println!("- {} {}", "PIN".cyan(), gen.path.to_string_lossy()),
+            }
+        } else {
+            println!("- {} {}", "OK ".green(), gen.path.to_string_lossy());
+        };
+    }
+    println!();
+}
+
 #[instrument(ret, level = "debug")]
 fn profiles_in_dir<P: AsRef<Path> + fmt::Debug>(dir: P) -> Vec<PathBuf> {
     let mut res = Vec::new();
     let dir = dir.as_ref();
+    let generation_regex = Regex::new(r"^(.*)-(\d+)-link$").unwrap();
 
     match dir.read_dir() {
         Ok(read_dir) => {
@@ -257,8 +533,6 @@ fn profiles_in_dir<P: AsRef<Path> + fmt::Debug>(dir: P) -> Vec<PathBuf> {
                                 .expect("Failed to get filename")
                                 .to_string_lossy();
 
-                            let generation_regex = Regex::new(r"^(.*)-(\d+)-link$").unwrap();
-
                             if generation_regex.captures(&name).is_some() {
                                 res.push(path);
                             }
@@ -278,12 +552,8 @@ fn profiles_in_dir<P: AsRef<Path> + fmt::Debug>(dir: P) -> Vec<PathBuf> {
     res
 }
 
-#[instrument(err, level = "debug")]
-fn cleanable_generations(
-    profile: &Path,
-    keep: u32,
-    keep_since: humantime::Duration,
-) -> Result<GenerationsTagged> {
+/// All the generations of a profile, none of them tagged for removal
+fn profile_generations(profile: &Path) -> Result<GenerationsTagged> {
     let name = profile
         .file_name()
         .context("Checking profile's name")?
@@ -291,6 +561,7 @@ fn cleanable_generations(
         .unwrap();
 
     let generation_regex = Regex::new(&format!(r"^{name}-(\d+)-link"))?;
+    let mut pins = pins::read(profile)?;
 
     let mut result = GenerationsTagged::new();
 
@@ -311,18 +582,35 @@ fn cleanable_generations(
                     .modified()
                     .context("Reading modified time")?;
 
+                let number: u32 = number.as_str().parse().unwrap();
+
                 result.insert(
                     Generation {
-                        number: number.as_str().parse().unwrap(),
+                        number,
                         last_modified,
                         path: path.clone(),
+                        pin: pins.remove(&u64::from(number)),
                     },
-                    true,
+                    false,
                 );
             }
         }
     }
 
+    Ok(result)
+}
+
+#[instrument(err, level = "debug")]
+fn cleanable_generations(
+    profile: &Path,
+    keep: u32,
+    keep_since: humantime::Duration,
+) -> Result<GenerationsTagged> {
+    let mut result = profile_generations(profile)?;
+    for tbr in result.values_mut() {
+        *tbr = true;
+    }
+
     let now = SystemTime::now();
     for (gen, tbr) in result.iter_mut() {
         match now.duration_since(gen.last_modified) {
@@ -340,6 +628,10 @@ fn cleanable_generations(
         *tbr = false;
     }
 
+    for (_, tbr) in result.iter_mut().filter(|(gen, _)| gen.pin.is_some()) {
+        *tbr = false;
+    }
+
     debug!("{:#?}", result);
     Ok(result)
 }
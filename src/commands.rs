@@ -1,7 +1,8 @@
 use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
 
 use color_eyre::{
-    eyre::{bail, Context},
+    eyre::{bail, eyre, Context},
     Result,
 };
 use subprocess::{Exec, ExitStatus, Redirection};
@@ -17,6 +18,9 @@ pub struct Command {
     command: OsString,
     args: Vec<OsString>,
     elevate: bool,
+    ssh: Option<String>,
+    merge_stderr: bool,
+    env: Vec<(OsString, OsString)>,
 }
 
 impl Command {
@@ -27,9 +31,24 @@ impl Command {
             command: command.as_ref().to_os_string(),
             args: vec![],
             elevate: false,
+            ssh: None,
+            merge_stderr: false,
+            env: vec![],
         }
     }
 
+    /// Run the command on this host through ssh instead of locally
+    pub fn ssh(mut self, host: Option<String>) -> Self {
+        self.ssh = host;
+        self
+    }
+
+    /// Also capture stderr in [`Command::run_capture`], interleaved with stdout
+    pub fn merge_stderr(mut self, merge_stderr: bool) -> Self {
+        self.merge_stderr = merge_stderr;
+        self
+    }
+
     pub fn elevate(mut self, elevate: bool) -> Self {
         self.elevate = elevate;
         self
@@ -56,13 +75,23 @@ impl Command {
         self
     }
 
+    /// Set an environment variable for the command, when run locally
+    pub fn env<K: AsRef<OsStr>, V: AsRef<OsStr>>(mut self, key: K, value: V) -> Self {
+        self.env
+            .push((key.as_ref().to_os_string(), value.as_ref().to_os_string()));
+        self
+    }
+
     pub fn message<S: AsRef<str>>(mut self, message: S) -> Self {
         self.message = Some(message.as_ref().to_string());
         self
     }
 
-    pub fn run(&self) -> Result<()> {
-        let cmd = if self.elevate {
+    /// With `capture`, remote sudo can't prompt, as its prompt would end up in the output
+    fn exec(&self, capture: bool) -> Result<Exec> {
+        let cmd = if let Some(host) = &self.ssh {
+            self.ssh_exec(host, capture)?
+        } else if self.elevate {
             let cmd = if cfg!(target_os = "macos") {
                 // Check for if sudo has the preserve-env flag
                 Exec::cmd("sudo").args(
@@ -86,9 +115,19 @@ impl Command {
             cmd.arg(&self.command).args(&self.args)
         } else {
             Exec::cmd(&self.command).args(&self.args)
-        }
-        .stderr(Redirection::None)
-        .stdout(Redirection::None);
+        };
+
+        Ok(self
+            .env
+            .iter()
+            .fold(cmd, |cmd, (key, value)| cmd.env(key, value)))
+    }
+
+    pub fn run(&self) -> Result<()> {
+        let cmd = self
+            .exec(false)?
+            .stderr(Redirection::None)
+            .stdout(Redirection::None);
 
         if let Some(m) = &self.message {
             info!("{}", m);
@@ -97,10 +136,18 @@ impl Command {
         debug!(?cmd);
 
         if !self.dry {
-            if let Some(m) = &self.message {
-                cmd.join().wrap_err(m.clone())?;
+            let exit = if let Some(m) = &self.message {
+                cmd.join().wrap_err(m.clone())?
             } else {
-                cmd.join()?;
+                cmd.join()?
+            };
+
+            match exit {
+                ExitStatus::Exited(0) => (),
+                other => match &self.message {
+                    Some(m) => bail!(eyre!(ExitError(other)).wrap_err(m.clone())),
+                    None => bail!(ExitError(other)),
+                },
             }
         }
 
@@ -108,9 +155,13 @@ impl Command {
     }
 
     pub fn run_capture(&self) -> Result<Option<String>> {
-        let cmd = Exec::cmd(&self.command)
-            .args(&self.args)
-            .stderr(Redirection::None)
+        let cmd = self
+            .exec(true)?
+            .stderr(if self.merge_stderr {
+                Redirection::Merge
+            } else {
+                Redirection::None
+            })
             .stdout(Redirection::Pipe);
 
         if let Some(m) = &self.message {
@@ -125,6 +176,45 @@ impl Command {
             Ok(None)
         }
     }
+
+    fn ssh_exec(&self, host: &str, capture: bool) -> Result<Exec> {
+        let mut remote = Vec::new();
+        if self.elevate {
+            remote.push("sudo");
+            if capture {
+                remote.push("-n");
+            }
+        }
+        for elem in std::iter::once(&self.command).chain(&self.args) {
+            match elem.to_str() {
+                Some(s) => remote.push(s),
+                None => bail!("Can't pass non UTF-8 argument {elem:?} through ssh"),
+            }
+        }
+
+        // sudo may need a terminal to ask for the password
+        ssh_exec(host, self.elevate && !capture, remote)
+    }
+}
+
+fn ssh_exec<'a, I>(host: &str, tty: bool, remote: I) -> Result<Exec>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    let remote = shlex::try_join(remote)?;
+
+    // Extra ssh options from NIX_SSHOPTS, the same variable nix copy and nixos-rebuild use
+    let ssh_opts = std::env::var("NIX_SSHOPTS")
+        .ok()
+        .and_then(|opts| shlex::split(&opts))
+        .unwrap_or_default();
+
+    let mut cmd = Exec::cmd("ssh").args(&ssh_opts);
+    if tty {
+        cmd = cmd.arg("-t");
+    }
+
+    Ok(cmd.arg(host).arg("--").arg(remote))
 }
 
 #[derive(Debug)]
@@ -132,6 +222,8 @@ pub struct Build {
     message: Option<String>,
     installable: Installable,
     extra_args: Vec<OsString>,
+    out_link: Option<PathBuf>,
+    build_host: Option<String>,
     nom: bool,
 }
 
@@ -141,6 +233,8 @@ impl Build {
             message: None,
             installable,
             extra_args: vec![],
+            out_link: None,
+            build_host: None,
             nom: false,
         }
     }
@@ -150,13 +244,19 @@ impl Build {
         self
     }
 
-    pub fn extra_arg<S: AsRef<OsStr>>(mut self, arg: S) -> Self {
-        self.extra_args.push(arg.as_ref().to_os_string());
+    pub fn nom(mut self, yes: bool) -> Self {
+        self.nom = yes;
         self
     }
 
-    pub fn nom(mut self, yes: bool) -> Self {
-        self.nom = yes;
+    pub fn out_link<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.out_link = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Evaluate locally, but realise the derivation on this host through ssh
+    pub fn build_host(mut self, host: Option<String>) -> Self {
+        self.build_host = host;
         self
     }
 
@@ -176,7 +276,15 @@ impl Build {
             info!("{}", m);
         }
 
+        if let Some(host) = &self.build_host {
+            return self.run_remote(host);
+        }
+
         let installable_args = self.installable.to_args();
+        let out_link_args = self
+            .out_link
+            .iter()
+            .flat_map(|p| [OsStr::new("--out-link"), p.as_os_str()]);
 
         let exit = if self.nom {
             let cmd = {
@@ -184,6 +292,7 @@ impl Build {
                     .arg("build")
                     .args(&installable_args)
                     .args(&["--log-format", "internal-json", "--verbose"])
+                    .args(&out_link_args.collect::<Vec<_>>())
                     .args(&self.extra_args)
                     .stdout(Redirection::Pipe)
                     .stderr(Redirection::Merge)
@@ -196,6 +305,7 @@ impl Build {
             let cmd = Exec::cmd("nix")
                 .arg("build")
                 .args(&installable_args)
+                .args(&out_link_args.collect::<Vec<_>>())
                 .args(&self.extra_args)
                 .stdout(Redirection::None)
                 .stderr(Redirection::Merge);
@@ -211,8 +321,134 @@ impl Build {
 
         Ok(())
     }
+
+    fn run_remote(&self, host: &str) -> Result<()> {
+        if let Installable::Store { .. } = self.installable {
+            bail!("Store paths are already built, --build-host can't be used with them");
+        }
+
+        let drv = Command::new("nix")
+            .args(["path-info", "--derivation"])
+            .args(self.installable.to_args())
+            .args(&self.extra_args)
+            .run_capture()?
+            .unwrap_or_default();
+        let drv = drv.trim();
+
+        if drv.is_empty() {
+            bail!("Failed to evaluate the derivation to build on {host}");
+        }
+
+        debug!(?drv);
+
+        Command::new("nix")
+            .args(["copy", "--derivation", "--to"])
+            .arg(format!("ssh://{host}"))
+            .arg(drv)
+            .message(format!("Copying derivation to {host}"))
+            .run()?;
+
+        let drv_outputs = format!("{drv}^*");
+        let remote_build = ["nix", "build", "--no-link", &drv_outputs];
+
+        let exit = if self.nom {
+            let cmd = {
+                ssh_exec(
+                    host,
+                    false,
+                    remote_build
+                        .into_iter()
+                        .chain(["--log-format", "internal-json", "--verbose"]),
+                )?
+                .stdout(Redirection::Pipe)
+                .stderr(Redirection::Merge)
+                    | Exec::cmd("nom").args(&["--json"])
+            }
+            .stdout(Redirection::None);
+            debug!(?cmd);
+            cmd.join()
+        } else {
+            let cmd = ssh_exec(host, false, remote_build)?
+                .stdout(Redirection::None)
+                .stderr(Redirection::Merge);
+
+            debug!(?cmd);
+            cmd.join()
+        };
+
+        match exit? {
+            ExitStatus::Exited(0) => (),
+            other => bail!(ExitError(other)),
+        }
+
+        let outputs = Command::new("nix-store")
+            .args(["--query", "--outputs", drv])
+            .run_capture()?
+            .unwrap_or_default();
+        let outputs: Vec<&str> = outputs.lines().collect();
+
+        debug!(?outputs);
+
+        Command::new("nix")
+            .args(["copy", "--from"])
+            .arg(format!("ssh://{host}"))
+            .args(&outputs)
+            .message(format!("Copying result from {host}"))
+            .run()?;
+
+        if let Some(out_link) = &self.out_link {
+            Command::new("nix")
+                .args(["build", "--out-link"])
+                .arg(out_link)
+                .args(&outputs)
+                .run()?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Write `contents` and a newline to `path` through a shell, so that it works with sudo and
+/// over ssh, creating the parent directory if needed
+pub fn write_file(
+    path: &Path,
+    contents: &str,
+    append: bool,
+    elevate: bool,
+    host: Option<String>,
+) -> Result<()> {
+    let script = if append {
+        r#"mkdir -p "$(dirname "$1")" && printf '%s\n' "$2" >> "$1""#
+    } else {
+        r#"mkdir -p "$(dirname "$1")" && printf '%s\n' "$2" > "$1""#
+    };
+
+    Command::new("sh")
+        .args(["-c", script, "sh"])
+        .arg(path)
+        .arg(contents)
+        .elevate(elevate)
+        .ssh(host)
+        .run()
 }
 
 #[derive(Debug, Error)]
 #[error("Command exited with status {0:?}")]
 pub struct ExitError(ExitStatus);
+
+/// Exit code of a command that failed in [`Command::run`], if it exited normally
+pub fn exit_code(err: &color_eyre::Report) -> Option<u32> {
+    err.chain()
+        .find_map(|err| err.downcast_ref::<ExitError>())
+        .and_then(|ExitError(status)| match status {
+            ExitStatus::Exited(code) => Some(*code),
+            _ => None,
+        })
+}
+
+#[test]
+fn test_exit_code() {
+    let err = eyre!(ExitError(ExitStatus::Exited(4))).wrap_err("Activating configuration");
+    assert_eq!(exit_code(&err), Some(4));
+    assert_eq!(exit_code(&eyre!("other")), None);
+}
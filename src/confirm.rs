@@ -0,0 +1,208 @@
+use std::ffi::OsStr;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::Duration;
+
+use chrono::{DateTime, Local, Utc};
+use color_eyre::eyre::{bail, Context};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+use crate::commands::{self, Command};
+use crate::Result;
+
+const STATE_FILE: &str = "/run/nh/confirm.json";
+const REVERT_UNIT: &str = "nh-confirm-revert";
+
+// Runs detached as root in a transient unit, so it survives the ssh session dying.
+// Arguments: state file, seconds to wait, system to restore, profile to reset or nothing,
+// generation to reset it to. switch-to-configuration holds the lock while it runs.
+const REVERT_SCRIPT: &str = r#"
+sleep "$2"
+[ -e "$1" ] || exit 0
+lock=/run/nixos/switch-to-configuration.lock
+if [ -e "$lock" ] && ! flock -n "$lock" true; then
+    echo "An activation is still running, not restoring $3"
+    exit 0
+fi
+echo "Configuration wasn't confirmed, restoring $3"
+"$3/bin/switch-to-configuration" test
+if [ -n "$4" ]; then
+    nix-env --profile "$4" --switch-generation "$5"
+    "$4/bin/switch-to-configuration" boot
+fi
+rm -f "$1"
+"#;
+
+/// Pending activation that reverts unless confirmed
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PendingConfirmation {
+    pub previous_system: PathBuf,
+    pub new_system: PathBuf,
+    pub deadline: DateTime<Utc>,
+    /// Generation to reset the system profile to, if the activation changed it
+    pub previous_generation: Option<u64>,
+}
+
+impl PendingConfirmation {
+    /// Write the state file and start the revert timer, before activating `new_system`
+    pub fn schedule(
+        &self,
+        system_profile: &Path,
+        elevate: bool,
+        host: Option<String>,
+    ) -> Result<()> {
+        let seconds = (self.deadline - Utc::now()).num_seconds().max(1);
+
+        commands::write_file(
+            Path::new(STATE_FILE),
+            &serde_json::to_string(self)?,
+            false,
+            elevate,
+            host.clone(),
+        )
+        .context("Writing the confirmation state")?;
+
+        Command::new("systemd-run")
+            .args([
+                "--unit",
+                REVERT_UNIT,
+                "--collect",
+                "--description",
+                "Revert unconfirmed NixOS configuration",
+                "--setenv",
+                "PATH=/run/current-system/sw/bin",
+                "sh",
+                "-c",
+                REVERT_SCRIPT,
+                "sh",
+                STATE_FILE,
+                &seconds.to_string(),
+            ])
+            .arg(&self.previous_system)
+            .arg(match self.previous_generation {
+                Some(_) => system_profile.as_os_str(),
+                None => OsStr::new(""),
+            })
+            .arg(
+                self.previous_generation
+                    .map(|generation| generation.to_string())
+                    .unwrap_or_default(),
+            )
+            .elevate(elevate)
+            .ssh(host)
+            .message(format!(
+                "Scheduling a revert to {} at {}",
+                self.previous_system.display(),
+                self.deadline.with_timezone(&Local).format("%H:%M:%S")
+            ))
+            .run()
+    }
+
+    /// Restart the countdown from now, once activation and the health checks are done
+    pub fn rearm(
+        &mut self,
+        within: Duration,
+        system_profile: &Path,
+        elevate: bool,
+        host: Option<String>,
+    ) -> Result<()> {
+        if Self::read(host.clone())?.is_none() {
+            bail!("The previous configuration was already restored while activating");
+        }
+
+        stop_revert(elevate, host.clone());
+        self.deadline = Utc::now() + chrono::Duration::from_std(within)?;
+        self.schedule(system_profile, elevate, host)
+    }
+
+    /// Read the pending confirmation of the local machine or `host`, if any
+    pub fn read(host: Option<String>) -> Result<Option<Self>> {
+        let contents = match host {
+            Some(_) => Command::new("sh")
+                .args(["-c", &format!("cat {STATE_FILE} 2>/dev/null || true")])
+                .ssh(host)
+                .run_capture()?
+                .unwrap_or_default(),
+            None => match std::fs::read_to_string(STATE_FILE) {
+                Ok(contents) => contents,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+                Err(err) => return Err(err).context("Reading the confirmation state"),
+            },
+        };
+
+        if contents.trim().is_empty() {
+            return Ok(None);
+        }
+
+        let pending = serde_json::from_str(&contents).context("Parsing the confirmation state")?;
+        debug!(?pending);
+        Ok(Some(pending))
+    }
+
+    /// Wait for the operator to press enter, until the deadline
+    pub fn wait_for_key(&self) -> bool {
+        if !std::io::stdin().is_terminal() {
+            return false;
+        }
+
+        let remaining = (self.deadline - Utc::now()).to_std().unwrap_or_default();
+        info!(
+            "Press enter within {} to keep the new configuration",
+            humantime::format_duration(Duration::from_secs(remaining.as_secs()))
+        );
+
+        // The reading thread is left behind if nothing is typed, it ends with the process
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            let mut line = String::new();
+            if std::io::stdin().read_line(&mut line).is_ok() {
+                let _ = tx.send(());
+            }
+        });
+
+        rx.recv_timeout(remaining).is_ok()
+    }
+}
+
+/// Keep the new configuration, cancelling the pending revert
+pub fn confirm(elevate: bool, host: Option<String>) -> Result<()> {
+    let Some(pending) = PendingConfirmation::read(host.clone())? else {
+        bail!("There is no activation waiting for confirmation");
+    };
+
+    if pending.deadline < Utc::now() {
+        warn!("The confirmation deadline has passed, the revert may already be running");
+    }
+
+    cancel(elevate, host)?;
+
+    info!("Confirmed {}", pending.new_system.display());
+    Ok(())
+}
+
+/// Remove the state file and stop the revert timer
+pub fn cancel(elevate: bool, host: Option<String>) -> Result<()> {
+    Command::new("rm")
+        .args(["-f", STATE_FILE])
+        .elevate(elevate)
+        .ssh(host.clone())
+        .run()
+        .context("Removing the confirmation state")?;
+
+    stop_revert(elevate, host);
+    Ok(())
+}
+
+fn stop_revert(elevate: bool, host: Option<String>) {
+    // The unit may have finished already, in which case there's nothing to stop
+    if let Err(err) = Command::new("systemctl")
+        .args(["stop", &format!("{REVERT_UNIT}.service")])
+        .elevate(elevate)
+        .ssh(host)
+        .run()
+    {
+        debug!(?err);
+    }
+}
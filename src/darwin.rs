@@ -1,15 +1,22 @@
+use std::path::Path;
+
 use color_eyre::eyre::{bail, Context};
 use tracing::{debug, info, warn};
 
+use crate::audit;
 use crate::commands;
 use crate::commands::Command;
+use crate::diff;
+use crate::generations;
+use crate::git;
+use crate::hooks::{self, Hooks, Phase};
+use crate::hosts;
 use crate::installable::Installable;
 use crate::interface::{DarwinArgs, DarwinRebuildArgs, DarwinReplArgs, DarwinSubcommand};
 use crate::nixos::toplevel_for;
 use crate::update::update;
 use crate::Result;
 
-const SYSTEM_PROFILE: &str = "/nix/var/nix/profiles/system";
 const CURRENT_PROFILE: &str = "/run/current-system";
 
 impl DarwinArgs {
@@ -24,6 +31,10 @@ impl DarwinArgs {
                 args.rebuild(Build)
             }
             DarwinSubcommand::Repl(args) => args.run(),
+            DarwinSubcommand::Hosts(args) => {
+                args.installable.reject_channel();
+                args.hosts("darwinConfigurations")
+            }
         }
     }
 }
@@ -66,17 +77,43 @@ fn get_hostname(hostname: Option<String>) -> Result<String> {
 
 impl DarwinRebuildArgs {
     fn rebuild(self, variant: DarwinRebuildVariant) -> Result<()> {
+        self.common.installable.reject_channel();
+
+        let name = match variant {
+            DarwinRebuildVariant::Switch => "switch",
+            DarwinRebuildVariant::Build => "build",
+        };
+        let mut audit = audit::Entry::new("darwin", name);
+        let result = hooks::wrap(name, |hooks| {
+            self.rebuild_with_hooks(variant, hooks, &mut audit)
+        });
+        audit.finish(&result);
+        result
+    }
+
+    fn rebuild_with_hooks(
+        self,
+        variant: DarwinRebuildVariant,
+        hooks: &mut Hooks,
+        audit: &mut audit::Entry,
+    ) -> Result<()> {
         use DarwinRebuildVariant::*;
 
         if nix::unistd::Uid::effective().is_root() {
             bail!("Don't run nh os as root. I will call sudo internally as needed");
         }
 
+        let profile = generations::system_profile(self.profile_name.as_deref())?;
+        let hostname = get_hostname(self.hostname)?;
+
         if self.update_args.update {
+            hooks.run(Phase::PreUpdate)?;
             update(&self.common.installable, self.update_args.update_input)?;
         }
 
-        let hostname = get_hostname(self.hostname)?;
+        git::check_untracked(&self.common.installable, self.common.add_intent)?;
+
+        hooks.hostname(&hostname);
 
         let out_path: Box<dyn crate::util::MaybeTempPath> = match self.common.out_link {
             Some(ref p) => Box::new(p.clone()),
@@ -88,38 +125,48 @@ impl DarwinRebuildArgs {
 
         debug!(?out_path);
 
-        let mut installable = self.common.installable.clone();
-        if let Installable::Flake {
-            ref mut attribute, ..
-        } = installable
-        {
-            // If user explicitly selects some other attribute, don't push darwinConfigurations
-            if attribute.is_empty() {
-                attribute.push(String::from("darwinConfigurations"));
-                attribute.push(hostname.clone());
-            }
-        }
+        hooks.run(Phase::PreBuild)?;
+
+        let (hostname, ()) = hosts::with_configuration(
+            &self.common.installable,
+            "darwinConfigurations",
+            hostname,
+            |hostname| {
+                let mut installable = self.common.installable.clone();
+                if let Installable::Flake {
+                    ref mut attribute, ..
+                } = installable
+                {
+                    // If user explicitly selects some other attribute, don't push the configurations
+                    if attribute.is_empty() {
+                        attribute.push(String::from("darwinConfigurations"));
+                        attribute.push(hostname.to_owned());
+                    }
+                }
 
-        let toplevel = toplevel_for(hostname, installable);
+                let toplevel = toplevel_for(hostname, installable, "toplevel");
 
-        commands::Build::new(toplevel)
-            .extra_arg("--out-link")
-            .extra_arg(out_path.get_path())
-            .extra_args(&self.extra_args)
-            .message("Building Darwin configuration")
-            .nom(!self.common.no_nom)
-            .run()?;
+                commands::Build::new(toplevel)
+                    .out_link(out_path.get_path())
+                    .build_host(self.common.build_host.clone())
+                    .extra_args(&self.extra_args)
+                    .message("Building Darwin configuration")
+                    .nom(!self.common.no_nom)
+                    .run()
+            },
+        )?;
+        hooks.hostname(&hostname);
+        audit.hostname = hostname;
 
         let target_profile = out_path.get_path().to_owned();
 
         target_profile.try_exists().context("Doesn't exist")?;
 
-        Command::new("nvd")
-            .arg("diff")
-            .arg(CURRENT_PROFILE)
-            .arg(&target_profile)
-            .message("Comparing changes")
-            .run()?;
+        hooks.out_path(&target_profile);
+        hooks.run(Phase::PostBuild)?;
+
+        let changes = diff::print_diff(Path::new(CURRENT_PROFILE), &target_profile)?;
+        audit.changes = Some(changes.summary());
 
         if self.common.ask && !self.common.dry && !matches!(variant, Build) {
             info!("Apply the config?");
@@ -131,8 +178,25 @@ impl DarwinRebuildArgs {
         }
 
         if let Switch = variant {
+            if !self.common.dry {
+                hooks.run(Phase::PreActivate)?;
+
+                audit.installable(&self.common.installable);
+                audit.out_path = Some(target_profile.clone());
+                audit.start(audit::Scope::System);
+            }
+
+            if self.profile_name.is_some() {
+                Command::new("mkdir")
+                    .args(["-p", "-m", "0755", generations::SYSTEM_PROFILES_DIR])
+                    .elevate(true)
+                    .dry(self.common.dry)
+                    .run()?;
+            }
+
             Command::new("nix")
-                .args(["build", "--no-link", "--profile", SYSTEM_PROFILE])
+                .args(["build", "--no-link", "--profile"])
+                .arg(&profile)
                 .arg(out_path.get_path())
                 .elevate(true)
                 .dry(self.common.dry)
@@ -152,6 +216,11 @@ impl DarwinRebuildArgs {
                 .message("Activating configuration")
                 .dry(self.common.dry)
                 .run()?;
+
+            if !self.common.dry {
+                audit.generation = generations::current(&profile).ok();
+                hooks.run(Phase::PostActivate)?;
+            }
         }
 
         // Make sure out_path is not accidentally dropped
@@ -164,7 +233,9 @@ impl DarwinRebuildArgs {
 
 impl DarwinReplArgs {
     fn run(self) -> Result<()> {
-        let mut target_installable = self.installable;
+        self.installable.reject_channel();
+
+        let target_installable = self.installable;
 
         if matches!(target_installable, Installable::Store { .. }) {
             bail!("Nix doesn't support nix store installables.");
@@ -172,20 +243,28 @@ impl DarwinReplArgs {
 
         let hostname = get_hostname(self.hostname)?;
 
-        if let Installable::Flake {
-            ref mut attribute, ..
-        } = target_installable
-        {
-            if attribute.is_empty() {
-                attribute.push(String::from("darwinConfigurations"));
-                attribute.push(hostname);
-            }
-        }
+        hosts::with_configuration(
+            &target_installable,
+            "darwinConfigurations",
+            hostname,
+            |hostname| {
+                let mut installable = target_installable.clone();
+                if let Installable::Flake {
+                    ref mut attribute, ..
+                } = installable
+                {
+                    if attribute.is_empty() {
+                        attribute.push(String::from("darwinConfigurations"));
+                        attribute.push(hostname.to_owned());
+                    }
+                }
 
-        Command::new("nix")
-            .arg("repl")
-            .args(target_installable.to_args())
-            .run()?;
+                Command::new("nix")
+                    .arg("repl")
+                    .args(installable.to_args())
+                    .run()
+            },
+        )?;
 
         Ok(())
     }
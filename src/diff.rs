@@ -0,0 +1,546 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::{Context, ContextCompat};
+use owo_colors::OwoColorize;
+use serde::Deserialize;
+use tracing::{debug, info};
+
+use crate::commands::Command;
+use crate::util::{format_size, format_size_delta};
+use crate::Result;
+
+/// Store path of a closure, with its size as reported by `nix path-info`
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct StorePath {
+    pub path: PathBuf,
+    pub pname: String,
+    pub version: String,
+    pub nar_size: u64,
+}
+
+/// Every store path reachable from some root
+#[derive(Debug, Clone)]
+pub struct Closure {
+    pub paths: Vec<StorePath>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PathInfoEntry {
+    path: String,
+    #[serde(rename = "narSize")]
+    nar_size: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct PathInfoMapEntry {
+    #[serde(rename = "narSize")]
+    nar_size: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum PathInfoOutput {
+    /// Nix < 2.19 returns a list of objects
+    List(Vec<PathInfoEntry>),
+    /// Nix >= 2.19 returns an object keyed by store path, invalid paths are null
+    Map(HashMap<String, Option<PathInfoMapEntry>>),
+}
+
+#[derive(Debug, Deserialize)]
+struct ClosureSizeEntry {
+    #[serde(rename = "closureSize")]
+    closure_size: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum ClosureSizeOutput {
+    List(Vec<ClosureSizeEntry>),
+    Map(HashMap<String, Option<ClosureSizeEntry>>),
+}
+
+/// Size of the closure of `root`, without listing every path in it
+pub fn closure_size(root: &Path) -> Result<u64> {
+    let output = Command::new("nix")
+        .args(["path-info", "--json", "--closure-size"])
+        .arg(root)
+        .run_capture()?
+        .unwrap_or_default();
+
+    let parsed: ClosureSizeOutput = serde_json::from_str(&output)
+        .with_context(|| format!("Parsing the closure size of {}", root.display()))?;
+
+    let size = match parsed {
+        ClosureSizeOutput::List(list) => list.into_iter().next(),
+        ClosureSizeOutput::Map(map) => map.into_values().flatten().next(),
+    };
+
+    size.map(|entry| entry.closure_size)
+        .with_context(|| format!("No closure size for {}", root.display()))
+}
+
+impl Closure {
+    /// Query the closure of `root` in the local store, or in the store at `store` (like `ssh://host`)
+    pub fn query(root: &Path, store: Option<&str>) -> Result<Self> {
+        let mut cmd = Command::new("nix").args(["path-info", "--json", "--recursive"]);
+        if let Some(store) = store {
+            cmd = cmd.args(["--store", store]);
+        }
+
+        let output = cmd.arg(root).run_capture()?.unwrap_or_default();
+
+        let parsed: PathInfoOutput = serde_json::from_str(&output)
+            .with_context(|| format!("Parsing the closure of {}", root.display()))?;
+
+        let entries: Vec<(String, u64)> = match parsed {
+            PathInfoOutput::List(list) => list.into_iter().map(|e| (e.path, e.nar_size)).collect(),
+            PathInfoOutput::Map(map) => map
+                .into_iter()
+                .filter_map(|(path, e)| Some((path, e?.nar_size)))
+                .collect(),
+        };
+
+        let paths = entries
+            .into_iter()
+            .map(|(path, nar_size)| {
+                let path = PathBuf::from(path);
+                let (pname, version) = parse_name(store_name(&path));
+                StorePath {
+                    pname: pname.to_owned(),
+                    version: version.to_owned(),
+                    path,
+                    nar_size,
+                }
+            })
+            .collect();
+
+        Ok(Self { paths })
+    }
+
+    pub fn size(&self) -> u64 {
+        self.paths.iter().map(|p| p.nar_size).sum()
+    }
+
+    fn by_pname(&self) -> BTreeMap<&str, Vec<&StorePath>> {
+        let mut res: BTreeMap<&str, Vec<&StorePath>> = BTreeMap::new();
+        for path in &self.paths {
+            res.entry(&path.pname).or_default().push(path);
+        }
+        res
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ChangeKind {
+    Upgraded,
+    Downgraded,
+    /// The set of versions changed, but not the newest one
+    Changed,
+    Added,
+    Removed,
+    /// Same versions, different store paths
+    Rebuilt,
+}
+
+#[derive(Debug, Clone)]
+pub struct PackageChange {
+    pub pname: String,
+    pub kind: ChangeKind,
+    pub old_versions: Vec<String>,
+    pub new_versions: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ClosureDiff {
+    pub changes: Vec<PackageChange>,
+    pub old_paths: usize,
+    pub new_paths: usize,
+    pub old_size: u64,
+    pub new_size: u64,
+}
+
+impl ClosureDiff {
+    pub fn new(old: &Closure, new: &Closure) -> Self {
+        let old_by_pname = old.by_pname();
+        let new_by_pname = new.by_pname();
+
+        let pnames: BTreeSet<&str> = old_by_pname
+            .keys()
+            .chain(new_by_pname.keys())
+            .copied()
+            .collect();
+
+        let mut changes = Vec::new();
+
+        for pname in pnames {
+            let old_paths = old_by_pname.get(pname).cloned().unwrap_or_default();
+            let new_paths = new_by_pname.get(pname).cloned().unwrap_or_default();
+
+            let old_versions = versions(&old_paths);
+            let new_versions = versions(&new_paths);
+
+            let kind = if old_paths.is_empty() {
+                ChangeKind::Added
+            } else if new_paths.is_empty() {
+                ChangeKind::Removed
+            } else if old_versions != new_versions {
+                match compare_versions(
+                    old_versions.last().map_or("", String::as_str),
+                    new_versions.last().map_or("", String::as_str),
+                ) {
+                    Ordering::Less => ChangeKind::Upgraded,
+                    Ordering::Greater => ChangeKind::Downgraded,
+                    Ordering::Equal => ChangeKind::Changed,
+                }
+            } else {
+                let old_set: BTreeSet<_> = old_paths.iter().map(|p| &p.path).collect();
+                let new_set: BTreeSet<_> = new_paths.iter().map(|p| &p.path).collect();
+                if old_set == new_set {
+                    continue;
+                }
+                ChangeKind::Rebuilt
+            };
+
+            changes.push(PackageChange {
+                pname: pname.to_owned(),
+                kind,
+                old_versions,
+                new_versions,
+            });
+        }
+
+        Self {
+            changes,
+            old_paths: old.paths.len(),
+            new_paths: new.paths.len(),
+            old_size: old.size(),
+            new_size: new.size(),
+        }
+    }
+
+    pub fn count(&self, kind: ChangeKind) -> usize {
+        self.changes.iter().filter(|c| c.kind == kind).count()
+    }
+
+    /// One line summary, like `2 upgraded, 1 added, +12.3 MiB`
+    pub fn summary(&self) -> String {
+        let kinds = [
+            (ChangeKind::Upgraded, "upgraded"),
+            (ChangeKind::Downgraded, "downgraded"),
+            (ChangeKind::Changed, "changed"),
+            (ChangeKind::Added, "added"),
+            (ChangeKind::Removed, "removed"),
+        ];
+
+        let mut parts: Vec<String> = kinds
+            .into_iter()
+            .map(|(kind, name)| (self.count(kind), name))
+            .filter(|(count, _)| *count > 0)
+            .map(|(count, name)| format!("{count} {name}"))
+            .collect();
+
+        if parts.is_empty() {
+            parts.push(String::from("no version changes"));
+        }
+
+        parts.push(format_size_delta(
+            self.new_size as i64 - self.old_size as i64,
+        ));
+
+        parts.join(", ")
+    }
+
+    pub fn print(&self, old: &Path, new: &Path) {
+        println!("{} {}", "<<<".red(), old.display());
+        println!("{} {}", ">>>".green(), new.display());
+
+        let width = self
+            .changes
+            .iter()
+            .filter(|c| c.kind != ChangeKind::Rebuilt)
+            .map(|c| c.pname.len())
+            .max()
+            .unwrap_or_default();
+
+        let sections = [
+            (
+                "Version changes:",
+                &[
+                    ChangeKind::Upgraded,
+                    ChangeKind::Downgraded,
+                    ChangeKind::Changed,
+                ][..],
+            ),
+            ("Added packages:", &[ChangeKind::Added][..]),
+            ("Removed packages:", &[ChangeKind::Removed][..]),
+        ];
+
+        for (title, kinds) in sections {
+            let changes: Vec<_> = self
+                .changes
+                .iter()
+                .filter(|c| kinds.contains(&c.kind))
+                .collect();
+
+            if changes.is_empty() {
+                continue;
+            }
+
+            println!("{}", title.bold());
+            for change in changes {
+                let tag = match change.kind {
+                    ChangeKind::Upgraded => "[U]".bright_cyan().to_string(),
+                    ChangeKind::Downgraded => "[D]".yellow().to_string(),
+                    ChangeKind::Changed => "[C]".magenta().to_string(),
+                    ChangeKind::Added => "[A]".green().to_string(),
+                    ChangeKind::Removed => "[R]".red().to_string(),
+                    ChangeKind::Rebuilt => unreachable!(),
+                };
+
+                let versions = match change.kind {
+                    ChangeKind::Added => change.new_versions.join(", "),
+                    ChangeKind::Removed => change.old_versions.join(", "),
+                    _ => format!(
+                        "{} -> {}",
+                        change.old_versions.join(", "),
+                        change.new_versions.join(", ")
+                    ),
+                };
+
+                println!("{tag} {:<width$}  {versions}", change.pname);
+            }
+        }
+
+        let rebuilt = self.count(ChangeKind::Rebuilt);
+        if rebuilt > 0 {
+            println!("{rebuilt} package(s) rebuilt without version changes");
+        }
+
+        if self.changes.is_empty() {
+            println!("No version or package changes.");
+        }
+
+        let size_delta = self.new_size as i64 - self.old_size as i64;
+        println!(
+            "Closure size: {} -> {} ({} paths), disk usage {} -> {} ({}{})",
+            self.old_paths,
+            self.new_paths,
+            format_delta(self.new_paths as i64 - self.old_paths as i64),
+            format_size(self.old_size),
+            format_size(self.new_size),
+            if size_delta < 0 { "-" } else { "+" },
+            format_size(size_delta.unsigned_abs()),
+        );
+    }
+}
+
+fn format_delta(delta: i64) -> String {
+    if delta < 0 {
+        delta.to_string()
+    } else {
+        format!("+{delta}")
+    }
+}
+
+/// Compare the closures of two store paths and print the package changes
+pub fn print_diff(old: &Path, new: &Path) -> Result<ClosureDiff> {
+    print_diff_with_store(old, None, new)
+}
+
+/// Like [`print_diff`], but `old` is queried from another store, like `ssh://host`
+pub fn print_diff_with_store(
+    old: &Path,
+    old_store: Option<&str>,
+    new: &Path,
+) -> Result<ClosureDiff> {
+    info!("Comparing changes");
+
+    let old_closure = Closure::query(old, old_store)?;
+    let new_closure = Closure::query(new, None)?;
+
+    let diff = ClosureDiff::new(&old_closure, &new_closure);
+    debug!(?diff);
+
+    diff.print(old, new);
+
+    Ok(diff)
+}
+
+fn versions(paths: &[&StorePath]) -> Vec<String> {
+    let mut res: Vec<String> = paths.iter().map(|p| p.version.clone()).collect();
+    res.sort_by(|a, b| compare_versions(a, b));
+    res.dedup();
+    res
+}
+
+/// Name of the store path, without the `/nix/store/<hash>-` prefix
+fn store_name(path: &Path) -> &str {
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default();
+
+    name.split_once('-').map_or(name, |(_, name)| name)
+}
+
+/// Split a derivation name into its pname and version, the same way Nix's `DrvName` does:
+/// the version starts after the first dash not followed by a letter.
+fn parse_name(name: &str) -> (&str, &str) {
+    let bytes = name.as_bytes();
+
+    for (i, c) in bytes.iter().enumerate() {
+        if *c == b'-'
+            && bytes
+                .get(i + 1)
+                .is_some_and(|next| !next.is_ascii_alphabetic())
+        {
+            return (&name[..i], &name[i + 1..]);
+        }
+    }
+
+    (name, "")
+}
+
+#[test]
+fn test_parse_name() {
+    assert_eq!(parse_name("hello-2.12.1"), ("hello", "2.12.1"));
+    assert_eq!(
+        parse_name("python3.11-requests-2.31.0"),
+        ("python3.11-requests", "2.31.0")
+    );
+    assert_eq!(
+        parse_name("linux-6.6.1-modules"),
+        ("linux", "6.6.1-modules")
+    );
+    assert_eq!(parse_name("etc"), ("etc", ""));
+    assert_eq!(
+        store_name(Path::new("/nix/store/abc-unit-dbus.service")),
+        "unit-dbus.service"
+    );
+}
+
+/// Split the next version component, skipping separators
+fn next_component(s: &str) -> (&str, &str) {
+    let s = s.trim_start_matches(['.', '-']);
+    let is_digit = s.starts_with(|c: char| c.is_ascii_digit());
+
+    let end = s
+        .find(|c: char| {
+            c == '.'
+                || c == '-'
+                || (if is_digit {
+                    !c.is_ascii_digit()
+                } else {
+                    c.is_ascii_digit()
+                })
+        })
+        .unwrap_or(s.len());
+
+    (&s[..end], &s[end..])
+}
+
+fn components_lt(c1: &str, c2: &str) -> bool {
+    let n1 = c1.parse::<u64>().ok();
+    let n2 = c2.parse::<u64>().ok();
+
+    match (n1, n2) {
+        (Some(n1), Some(n2)) => n1 < n2,
+        _ if c1.is_empty() && n2.is_some() => true,
+        _ if c1 == "pre" && c2 != "pre" => true,
+        _ if c2 == "pre" => false,
+        // Assume that `2.3a' < `2.3.1'
+        (_, Some(_)) => true,
+        (Some(_), _) => false,
+        _ => c1 < c2,
+    }
+}
+
+/// Compare two versions, following Nix's `builtins.compareVersions`
+pub fn compare_versions(v1: &str, v2: &str) -> Ordering {
+    let (mut v1, mut v2) = (v1, v2);
+
+    while !v1.is_empty() || !v2.is_empty() {
+        let (c1, rest1) = next_component(v1);
+        let (c2, rest2) = next_component(v2);
+
+        if components_lt(c1, c2) {
+            return Ordering::Less;
+        } else if components_lt(c2, c1) {
+            return Ordering::Greater;
+        }
+
+        (v1, v2) = (rest1, rest2);
+    }
+
+    Ordering::Equal
+}
+
+#[test]
+fn test_compare_versions() {
+    assert_eq!(compare_versions("1.0", "2.3"), Ordering::Less);
+    assert_eq!(compare_versions("2.1", "2.3"), Ordering::Less);
+    assert_eq!(compare_versions("2.3", "2.3"), Ordering::Equal);
+    assert_eq!(compare_versions("2.5", "2.3"), Ordering::Greater);
+    assert_eq!(compare_versions("3.1", "2.3"), Ordering::Greater);
+    assert_eq!(compare_versions("2.3.1", "2.3"), Ordering::Greater);
+    assert_eq!(compare_versions("2.3.1", "2.3a"), Ordering::Greater);
+    assert_eq!(compare_versions("2.3pre1", "2.3"), Ordering::Less);
+    assert_eq!(compare_versions("2.3pre3", "2.3pre12"), Ordering::Less);
+    assert_eq!(compare_versions("2.3a", "2.3c"), Ordering::Less);
+    assert_eq!(compare_versions("2.3pre1", "2.3c"), Ordering::Less);
+    assert_eq!(compare_versions("2.3pre1", "2.3q"), Ordering::Less);
+}
+
+#[test]
+fn test_closure_diff() {
+    let closure = |paths: &[&str]| Closure {
+        paths: paths
+            .iter()
+            .map(|p| {
+                let path = PathBuf::from(p);
+                let (pname, version) = parse_name(store_name(&path));
+                StorePath {
+                    pname: pname.to_owned(),
+                    version: version.to_owned(),
+                    path: path.clone(),
+                    nar_size: 10,
+                }
+            })
+            .collect(),
+    };
+
+    let old = closure(&[
+        "/nix/store/a-firefox-120.0",
+        "/nix/store/b-bash-5.2",
+        "/nix/store/c-foo-2.0",
+        "/nix/store/d-etc",
+        "/nix/store/e-same-1.0",
+    ]);
+    let new = closure(&[
+        "/nix/store/f-firefox-121.0",
+        "/nix/store/b-bash-5.2",
+        "/nix/store/g-bar-1.0",
+        "/nix/store/h-etc",
+        "/nix/store/i-foo-1.0",
+        "/nix/store/e-same-1.0",
+        "/nix/store/j-bar-1.0-man",
+    ]);
+
+    let diff = ClosureDiff::new(&old, &new);
+    let kind_of = |pname: &str| {
+        diff.changes
+            .iter()
+            .find(|c| c.pname == pname)
+            .map(|c| c.kind)
+    };
+
+    assert_eq!(kind_of("firefox"), Some(ChangeKind::Upgraded));
+    assert_eq!(kind_of("foo"), Some(ChangeKind::Downgraded));
+    assert_eq!(kind_of("bar"), Some(ChangeKind::Added));
+    assert_eq!(kind_of("etc"), Some(ChangeKind::Rebuilt));
+    assert_eq!(kind_of("same"), None);
+    assert_eq!(kind_of("bash"), None);
+    assert_eq!(diff.new_size - diff.old_size, 20);
+}
@@ -2,16 +2,34 @@ use std::fs;
 use std::path::{Path, PathBuf};
 use std::process;
 
-use chrono::{DateTime, Local, TimeZone, Utc};
+use chrono::{DateTime, Local, Utc};
+use color_eyre::eyre::{bail, Context, ContextCompat};
+use color_eyre::Result;
+use owo_colors::OwoColorize;
+use serde::Serialize;
 use tracing::debug;
 
-#[derive(Debug)]
+use crate::git::GitState;
+use crate::pins::Pin;
+use crate::util::{format_size, format_size_delta};
+
+// Closure growth between generations that gets highlighted
+const GROWTH_WARNING: i64 = 1024 * 1024 * 1024;
+
+/// The system the machine booted into
+pub const BOOTED_SYSTEM: &str = "/run/booted-system";
+const SYSTEM_PROFILE: &str = "/nix/var/nix/profiles/system";
+
+/// Directory of the extra system profiles created with --profile-name
+pub const SYSTEM_PROFILES_DIR: &str = "/nix/var/nix/profiles/system-profiles";
+
+#[derive(Debug, Serialize)]
 pub struct GenerationInfo {
     /// Number of a generation
-    pub number: String,
+    pub number: u64,
 
     /// Date on switch a generation was built
-    pub date: String,
+    pub date: Option<DateTime<Utc>>,
 
     /// NixOS version derived from `nixos-version`
     pub nixos_version: String,
@@ -20,14 +38,37 @@ pub struct GenerationInfo {
     pub kernel_version: String,
 
     /// Revision for a configuration. This will be the value
-    /// set in `config.system.configurationRevision`
+    /// set in `config.system.configurationRevision`, or the git state nh recorded
     pub configuration_revision: String,
 
+    /// Git state of the local flake nh built the generation from, if any
+    pub git: Option<GitState>,
+
     /// Specialisations, if any.
     pub specialisations: Vec<String>,
 
     /// Whether a given generation is the current one.
     pub current: bool,
+
+    /// Whether a given generation is the one the system booted into.
+    pub booted: bool,
+
+    /// Closure size in bytes, if it was computed.
+    pub closure_size: Option<u64>,
+
+    /// Pin that protects the generation from nh clean, if any.
+    pub pin: Option<Pin>,
+}
+
+/// The default system profile, or the one named `name` in [`SYSTEM_PROFILES_DIR`]
+pub fn system_profile(name: Option<&str>) -> Result<PathBuf> {
+    match name {
+        None => Ok(PathBuf::from(SYSTEM_PROFILE)),
+        Some(name) if name.is_empty() || name.contains('/') || name.starts_with('.') => {
+            bail!("Invalid profile name `{name}`")
+        }
+        Some(name) => Ok(Path::new(SYSTEM_PROFILES_DIR).join(name)),
+    }
 }
 
 pub fn from_dir(generation_dir: &Path) -> Option<u64> {
@@ -42,19 +83,64 @@ pub fn from_dir(generation_dir: &Path) -> Option<u64> {
         })
 }
 
-pub fn describe(generation_dir: &Path, current_profile: &Path) -> Option<GenerationInfo> {
-    let generation_number = from_dir(generation_dir)?;
-    let nixos_version = fs::read_to_string(generation_dir.join("nixos-version"))
-        .unwrap_or_else(|_| "Unknown".to_string());
+/// List the `<profile>-<N>-link` generations of a profile, sorted by generation number
+pub fn list(profile: &Path) -> Result<Vec<(u64, PathBuf)>> {
+    let profile_name = profile
+        .file_name()
+        .and_then(|name| name.to_str())
+        .context("Checking profile's name")?;
+    let prefix = format!("{profile_name}-");
+    let profile_dir = profile.parent().unwrap_or_else(|| Path::new("."));
 
-    let kernel_dir = generation_dir
-        .join("kernel")
-        .canonicalize()
-        .ok()
-        .and_then(|path| path.parent().map(|p| p.to_path_buf()))
-        .unwrap_or_else(|| PathBuf::from("Unknown"));
+    let mut generations: Vec<_> = fs::read_dir(profile_dir)
+        .context("Reading profile's generations")?
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            let name = path.file_name()?.to_str()?;
+            let number = name.strip_prefix(&prefix)?.strip_suffix("-link")?;
+            Some((number.parse::<u64>().ok()?, path))
+        })
+        .collect();
+
+    generations.sort_by_key(|(number, _)| *number);
+    Ok(generations)
+}
+
+/// Generation number the profile symlink currently points to
+pub fn current(profile: &Path) -> Result<u64> {
+    let target = fs::read_link(profile)
+        .with_context(|| format!("Reading profile link {}", profile.display()))?;
+    from_dir(&target).with_context(|| {
+        format!(
+            "Profile {} doesn't point to a generation",
+            profile.display()
+        )
+    })
+}
 
-    let kernel_version = fs::read_dir(kernel_dir.join("lib/modules"))
+/// Names of the specialisations of a generation, sorted
+pub fn specialisations(generation_dir: &Path) -> Vec<String> {
+    let mut specialisations = fs::read_dir(generation_dir.join("specialisation"))
+        .map(|entries| {
+            entries
+                .filter_map(|entry| {
+                    entry
+                        .ok()
+                        .and_then(|e| e.file_name().to_str().map(String::from))
+                })
+                .collect::<Vec<String>>()
+        })
+        .unwrap_or_default();
+
+    specialisations.sort();
+    specialisations
+}
+
+/// Versions of a resolved kernel image, from the modules shipped next to it
+pub fn kernel_version(kernel: &Path) -> Option<String> {
+    let kernel_dir = kernel.parent()?;
+
+    fs::read_dir(kernel_dir.join("lib/modules"))
         .map(|entries| {
             let mut versions = vec![];
             for entry in entries.filter_map(Result::ok) {
@@ -64,8 +150,21 @@ pub fn describe(generation_dir: &Path, current_profile: &Path) -> Option<Generat
             }
             versions.join(", ")
         })
+        .ok()
+}
+
+pub fn describe(generation_dir: &Path, current_profile: &Path) -> Option<GenerationInfo> {
+    let generation_number = from_dir(generation_dir)?;
+    let nixos_version = fs::read_to_string(generation_dir.join("nixos-version"))
         .unwrap_or_else(|_| "Unknown".to_string());
 
+    let kernel_version = generation_dir
+        .join("kernel")
+        .canonicalize()
+        .ok()
+        .and_then(|kernel| kernel_version(&kernel))
+        .unwrap_or_else(|| "Unknown".to_string());
+
     let configuration_revision = {
         let nixos_version_path = generation_dir.join("sw/bin/nixos-version");
         if nixos_version_path.exists() {
@@ -82,83 +181,157 @@ pub fn describe(generation_dir: &Path, current_profile: &Path) -> Option<Generat
         }
     };
 
-    let build_date = fs::metadata(generation_dir)
-        .and_then(|metadata| metadata.created().or_else(|_| metadata.modified()))
-        .map(|system_time| {
-            let duration = system_time
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap_or_default();
-            DateTime::<Utc>::from(std::time::UNIX_EPOCH + duration).to_rfc3339()
-        })
-        .unwrap_or_else(|_| "Unknown".to_string());
+    let git = GitState::load(generation_dir);
+    let configuration_revision = match &git {
+        Some(git) if configuration_revision.is_empty() => git.describe(),
+        _ => configuration_revision,
+    };
 
-    let specialisations = {
-        let specialisation_path = generation_dir.join("specialisation");
-        if specialisation_path.exists() {
-            fs::read_dir(specialisation_path)
-                .map(|entries| {
-                    entries
-                        .filter_map(|entry| {
-                            entry
-                                .ok()
-                                .and_then(|e| e.file_name().to_str().map(String::from))
-                        })
-                        .collect::<Vec<String>>()
-                })
-                .unwrap_or_default()
-        } else {
-            vec![]
-        }
+    let build_date = build_date(generation_dir);
+
+    let specialisations = specialisations(generation_dir);
+
+    let canonical_gen_dir = generation_dir.canonicalize().ok();
+    let points_to = |profile: &Path| {
+        canonical_gen_dir.is_some() && profile.canonicalize().ok() == canonical_gen_dir
     };
 
-    let current = generation_dir
-        .canonicalize()
-        .ok()
-        .map(|canonical_gen_dir| {
-            current_profile
-                .canonicalize()
-                .ok()
-                .map(|canonical_current| canonical_gen_dir == canonical_current)
-                .unwrap_or(false)
-        })
-        .unwrap_or(false);
+    let current = points_to(current_profile);
+    let booted = points_to(Path::new(BOOTED_SYSTEM));
 
     Some(GenerationInfo {
-        number: generation_number.to_string(),
+        number: generation_number,
         date: build_date,
         nixos_version,
         kernel_version,
         configuration_revision,
+        git,
         specialisations,
         current,
+        booted,
+        closure_size: None,
+        pin: None,
     })
 }
 
-pub fn print_info(mut generations: Vec<GenerationInfo>) {
-    // Get path information for the *current generation* from /run/current-system
-    // and split it by whitespace to get the size (second part). This should be
-    // safe enough, in theory.
-    let path_info = process::Command::new("nix")
-        .arg("path-info")
-        .arg("-Sh")
-        .arg("/run/current-system")
-        .output();
-
-    let closure = if let Ok(output) = path_info {
-        let size_info = String::from_utf8_lossy(&output.stdout);
-        let size = size_info.split_whitespace().nth(1).unwrap_or("Unknown");
-        size.to_string()
-    } else {
-        "Unknown".to_string()
-    };
+fn build_date(generation_dir: &Path) -> Option<DateTime<Utc>> {
+    fs::metadata(generation_dir)
+        .and_then(|metadata| metadata.created().or_else(|_| metadata.modified()))
+        .map(DateTime::<Utc>::from)
+        .ok()
+}
+
+#[derive(Debug, Serialize)]
+pub struct HomeGenerationInfo {
+    /// Home-manager profile the generation belongs to
+    pub profile: PathBuf,
+
+    /// Number of a generation
+    pub number: u64,
+
+    /// Date on switch a generation was built
+    pub date: Option<DateTime<Utc>>,
+
+    /// Home-manager version from `hm-version`
+    pub home_manager_version: String,
+
+    /// Number of packages in `home.packages`, if they could be queried
+    pub packages: Option<usize>,
+
+    /// Whether a given generation is the current one.
+    pub current: bool,
+
+    /// Closure size in bytes, if it was computed.
+    pub closure_size: Option<u64>,
+}
+
+pub fn describe_home(generation_dir: &Path, profile: &Path) -> Option<HomeGenerationInfo> {
+    let number = from_dir(generation_dir)?;
 
-    // Sort generations by numeric value of the generation number
-    generations.sort_by_key(|gen| gen.number.parse::<u64>().unwrap_or(0));
+    let home_manager_version = fs::read_to_string(generation_dir.join("hm-version"))
+        .map(|version| version.trim().to_string())
+        .unwrap_or_else(|_| "Unknown".to_string());
+
+    // home-path is the buildEnv of home.packages, so its references are the packages
+    let packages = process::Command::new("nix-store")
+        .args(["--query", "--references"])
+        .arg(generation_dir.join("home-path"))
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).lines().count());
+
+    let canonical_gen_dir = generation_dir.canonicalize().ok();
+    let current = canonical_gen_dir.is_some() && profile.canonicalize().ok() == canonical_gen_dir;
+
+    Some(HomeGenerationInfo {
+        profile: profile.to_owned(),
+        number,
+        date: build_date(generation_dir),
+        home_manager_version,
+        packages,
+        current,
+        closure_size: None,
+    })
+}
 
-    // Retrieve the current generation
-    let current_generation = generations
+/// Print the generations of a home-manager profile as a table, `generations` must be sorted
+/// by number
+pub fn print_home_info(profile: &Path, generations: &[HomeGenerationInfo]) {
+    println!("{}", format!("Profile: {}", profile.display()).bold());
+
+    let max_version_len = generations
         .iter()
-        .max_by_key(|gen| gen.number.parse::<u64>().unwrap_or(0));
+        .map(|g| g.home_manager_version.len())
+        .max()
+        .unwrap_or_default()
+        .max(12);
+
+    println!(
+        "{:<13} {:<20} {:<width_version$} {:>8} {:>12}",
+        "Generation No",
+        "Build Date",
+        "Home Manager",
+        "Packages",
+        "Closure Size",
+        width_version = max_version_len,
+    );
+
+    for generation in generations.iter().rev() {
+        let formatted_date = generation
+            .date
+            .map(|date| {
+                date.with_timezone(&Local)
+                    .format("%Y-%m-%d %H:%M:%S")
+                    .to_string()
+            })
+            .unwrap_or_else(|| "Unknown".to_string());
+
+        println!(
+            "{:<13} {:<20} {:<width_version$} {:>8} {:>12}",
+            format!(
+                "{}{}",
+                generation.number,
+                if generation.current { " (current)" } else { "" }
+            ),
+            formatted_date,
+            generation.home_manager_version,
+            generation
+                .packages
+                .map(|packages| packages.to_string())
+                .unwrap_or_else(|| "-".to_string()),
+            generation
+                .closure_size
+                .map(format_size)
+                .unwrap_or_else(|| "-".to_string()),
+            width_version = max_version_len,
+        );
+    }
+}
+
+/// Print generations as a table, `generations` must be sorted by number
+pub fn print_info(generations: &[GenerationInfo]) {
+    let current_generation = generations.iter().find(|gen| gen.current);
     debug!(?current_generation);
 
     if let Some(current) = current_generation {
@@ -167,6 +340,11 @@ pub fn print_info(mut generations: Vec<GenerationInfo>) {
         println!("Error getting current generation!");
     }
 
+    let closure = current_generation
+        .and_then(|gen| gen.closure_size)
+        .map(format_size)
+        .unwrap_or_else(|| "Unknown".to_string());
+
     println!("Closure Size: {}", closure);
     println!();
 
@@ -183,30 +361,40 @@ pub fn print_info(mut generations: Vec<GenerationInfo>) {
         .max()
         .unwrap_or(12); // arbitrary value
 
+    let max_pin_len = generations
+        .iter()
+        .map(|g| pin_label(g).len())
+        .max()
+        .unwrap_or_default()
+        .max(3);
+
     println!(
-        "{:<13} {:<20} {:<width_nixos$} {:<width_kernel$} {:<22} Specialisations",
+        "{:<13} {:<20} {:<width_nixos$} {:<width_kernel$} {:<22} {:>12} {:>12} {:<width_pin$} Specialisations",
         "Generation No",
         "Build Date",
         "NixOS Version",
         "Kernel",
         "Configuration Revision",
+        "Closure Size",
+        "Size Change",
+        "Pin",
         width_nixos = max_nixos_version_len,
-        width_kernel = max_kernel_len
+        width_kernel = max_kernel_len,
+        width_pin = max_pin_len
     );
 
+    let deltas = size_deltas(generations);
+
     // Print generations in descending order
-    for generation in generations.iter().rev() {
-        let date_str = &generation.date;
-        let date = DateTime::parse_from_rfc3339(date_str)
-            .map(|dt| dt.with_timezone(&Local))
-            .unwrap_or_else(|err| {
-                eprintln!(
-                    "Failed to parse date `{}` with error: {}. Using default date.",
-                    date_str, err
-                );
-                Local.timestamp_opt(0, 0).unwrap() // default to Unix epoch
-            });
-        let formatted_date = date.format("%Y-%m-%d %H:%M:%S").to_string();
+    for (generation, delta) in generations.iter().zip(deltas).rev() {
+        let formatted_date = generation
+            .date
+            .map(|date| {
+                date.with_timezone(&Local)
+                    .format("%Y-%m-%d %H:%M:%S")
+                    .to_string()
+            })
+            .unwrap_or_else(|| "Unknown".to_string());
         let specialisations = generation
             .specialisations
             .iter()
@@ -214,8 +402,22 @@ pub fn print_info(mut generations: Vec<GenerationInfo>) {
             .collect::<Vec<String>>()
             .join(" ");
 
+        let size = generation
+            .closure_size
+            .map(format_size)
+            .unwrap_or_else(|| "-".to_string());
+
+        // Growing by a gigabyte or more is worth noticing
+        let delta = match delta {
+            Some(delta) if delta >= GROWTH_WARNING => format!("{:>12}", format_size_delta(delta))
+                .red()
+                .to_string(),
+            Some(delta) => format!("{:>12}", format_size_delta(delta)),
+            None => format!("{:>12}", "-"),
+        };
+
         println!(
-            "{:<13} {:<20} {:<width_nixos$} {:<width_kernel$} {:<25} {}",
+            "{:<13} {:<20} {:<width_nixos$} {:<width_kernel$} {:<22} {:>12} {} {:<width_pin$} {}",
             format!(
                 "{}{}",
                 generation.number,
@@ -225,9 +427,155 @@ pub fn print_info(mut generations: Vec<GenerationInfo>) {
             generation.nixos_version,
             generation.kernel_version,
             generation.configuration_revision,
+            size,
+            delta,
+            pin_label(generation),
             specialisations,
             width_nixos = max_nixos_version_len,
-            width_kernel = max_kernel_len
+            width_kernel = max_kernel_len,
+            width_pin = max_pin_len
         );
     }
 }
+
+/// Change in closure size of each generation, relative to the one listed before it
+fn size_deltas(generations: &[GenerationInfo]) -> Vec<Option<i64>> {
+    let mut previous = None;
+    generations
+        .iter()
+        .map(|generation| {
+            let delta = previous
+                .zip(generation.closure_size)
+                .map(|(previous, size)| size as i64 - previous as i64);
+            previous = generation.closure_size;
+            delta
+        })
+        .collect()
+}
+
+/// Closure size history of the generations, oldest first
+pub fn print_sparkline(generations: &[GenerationInfo]) {
+    let sizes: Vec<Option<u64>> = generations.iter().map(|g| g.closure_size).collect();
+    println!();
+    println!("Size history: {}", sparkline(&sizes));
+}
+
+fn sparkline(values: &[Option<u64>]) -> String {
+    const BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+    let known = values.iter().flatten();
+    let (Some(min), Some(max)) = (known.clone().min(), known.max()) else {
+        return String::new();
+    };
+
+    values
+        .iter()
+        .map(|value| match value {
+            Some(value) if max > min => {
+                let index = (value - min) * (BARS.len() as u64 - 1) / (max - min);
+                BARS[index as usize]
+            }
+            Some(_) => BARS[0],
+            None => ' ',
+        })
+        .collect()
+}
+
+/// Label of the pin for the table, or `*` for pins without a label
+fn pin_label(generation: &GenerationInfo) -> &str {
+    match &generation.pin {
+        Some(Pin { label: Some(label) }) => label,
+        Some(Pin { label: None }) => "*",
+        None => "",
+    }
+}
+
+/// Print generations as a JSON array
+pub fn print_json(generations: &[GenerationInfo]) -> Result<()> {
+    println!("{}", serde_json::to_string_pretty(generations)?);
+    Ok(())
+}
+
+/// Print generations as CSV, with a header row
+pub fn print_csv(generations: &[GenerationInfo]) {
+    println!("number,date,nixos_version,kernel_version,configuration_revision,specialisations,current,booted,closure_size,pinned,pin_label");
+
+    for generation in generations {
+        let fields = [
+            generation.number.to_string(),
+            generation
+                .date
+                .map(|date| date.to_rfc3339())
+                .unwrap_or_default(),
+            generation.nixos_version.clone(),
+            generation.kernel_version.clone(),
+            generation.configuration_revision.clone(),
+            generation.specialisations.join(" "),
+            generation.current.to_string(),
+            generation.booted.to_string(),
+            generation
+                .closure_size
+                .map(|size| size.to_string())
+                .unwrap_or_default(),
+            generation.pin.is_some().to_string(),
+            generation
+                .pin
+                .as_ref()
+                .and_then(|pin| pin.label.clone())
+                .unwrap_or_default(),
+        ];
+
+        let fields: Vec<String> = fields.iter().map(|f| csv_field(f)).collect();
+        println!("{}", fields.join(","));
+    }
+}
+
+pub fn print_home_csv(generations: &[HomeGenerationInfo]) {
+    println!("profile,number,date,home_manager_version,packages,current,closure_size");
+
+    for generation in generations {
+        let fields = [
+            generation.profile.to_string_lossy().into_owned(),
+            generation.number.to_string(),
+            generation
+                .date
+                .map(|date| date.to_rfc3339())
+                .unwrap_or_default(),
+            generation.home_manager_version.clone(),
+            generation
+                .packages
+                .map(|packages| packages.to_string())
+                .unwrap_or_default(),
+            generation.current.to_string(),
+            generation
+                .closure_size
+                .map(|size| size.to_string())
+                .unwrap_or_default(),
+        ];
+
+        let fields: Vec<String> = fields.iter().map(|f| csv_field(f)).collect();
+        println!("{}", fields.join(","));
+    }
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[test]
+fn test_csv_field() {
+    assert_eq!(csv_field("24.05"), "24.05");
+    assert_eq!(csv_field("6.1, 6.6"), "\"6.1, 6.6\"");
+    assert_eq!(csv_field("a\"b"), "\"a\"\"b\"");
+}
+
+#[test]
+fn test_sparkline() {
+    assert_eq!(sparkline(&[Some(1), Some(8), None, Some(4)]), "▁█ ▄");
+    assert_eq!(sparkline(&[Some(5), Some(5)]), "▁▁");
+    assert_eq!(sparkline(&[None]), "");
+}
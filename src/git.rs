@@ -0,0 +1,158 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::ContextCompat;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::commands::{self, Command};
+use crate::installable::Installable;
+use crate::Result;
+
+// Keyed by the name of the toplevel store path, so the same build shares one entry
+const REVISIONS_DIR: &str = "/var/lib/nh/revisions";
+
+/// Git state of a local flake, recorded for the generations built from it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GitState {
+    pub commit: String,
+    /// None on a detached HEAD
+    pub branch: Option<String>,
+    /// Whether tracked files have uncommitted changes
+    pub dirty: bool,
+}
+
+impl GitState {
+    /// State of the repository containing a local flake installable, if it is one
+    pub fn detect(installable: &Installable) -> Option<Self> {
+        let Installable::Flake { reference, .. } = installable else {
+            return None;
+        };
+        let path = local_path(reference)?;
+
+        let commit = git(&path, &["rev-parse", "HEAD"])?;
+        let branch = git(&path, &["rev-parse", "--abbrev-ref", "HEAD"]).filter(|b| b != "HEAD");
+        // Untracked files aren't part of the flake, so they don't make it dirty
+        let dirty = git(&path, &["status", "--porcelain", "--untracked-files=no"]).is_some();
+
+        let state = Self {
+            commit,
+            branch,
+            dirty,
+        };
+        debug!(?state);
+        Some(state)
+    }
+
+    /// Short description, like `1a2b3c4d5e6f-dirty (main)`
+    pub fn describe(&self) -> String {
+        let mut description: String = self.commit.chars().take(12).collect();
+        if self.dirty {
+            description.push_str("-dirty");
+        }
+        if let Some(branch) = &self.branch {
+            description.push_str(&format!(" ({branch})"));
+        }
+        description
+    }
+
+    /// Record the state for `toplevel`, on the local machine or `host`
+    pub fn save(&self, toplevel: &Path, elevate: bool, host: Option<String>) -> Result<()> {
+        let path = revision_file(toplevel).context("Toplevel has no file name")?;
+
+        commands::write_file(&path, &serde_json::to_string(self)?, false, elevate, host)
+    }
+
+    /// State recorded for a generation, if nh built it from a local git flake
+    pub fn load(generation_dir: &Path) -> Option<Self> {
+        let path = revision_file(&generation_dir.canonicalize().ok()?)?;
+        let contents = fs::read_to_string(path).ok()?;
+        serde_json::from_str(&contents).ok()
+    }
+}
+
+/// Warn about untracked files under a local git flake, which nix leaves out of the flake
+/// source. With `add_intent` they are added with `git add -N` instead, so that nix sees them.
+pub fn check_untracked(installable: &Installable, add_intent: bool) -> Result<()> {
+    let Installable::Flake { reference, .. } = installable else {
+        return Ok(());
+    };
+    let Some(path) = local_path(reference) else {
+        return Ok(());
+    };
+    let Some(untracked) = git(&path, &["ls-files", "--others", "--exclude-standard"]) else {
+        return Ok(());
+    };
+    let files: Vec<&str> = untracked.lines().collect();
+
+    if add_intent {
+        Command::new("git")
+            .arg("-C")
+            .arg(&path)
+            .args(["add", "--intent-to-add", "--"])
+            .args(&files)
+            .message(format!("Adding {} untracked files to git", files.len()))
+            .run()?;
+        return Ok(());
+    }
+
+    warn!(
+        "{} files in {} aren't tracked by git, so the flake can't see them:",
+        files.len(),
+        path.display()
+    );
+    for file in &files {
+        eprintln!("  {file}");
+    }
+    warn!("Add them with `git add -N`, or pass --add-intent to let nh do it");
+
+    Ok(())
+}
+
+fn revision_file(toplevel: &Path) -> Option<PathBuf> {
+    let name = toplevel.file_name()?.to_str()?;
+    Some(Path::new(REVISIONS_DIR).join(format!("{name}.json")))
+}
+
+/// Run git in `dir`, returning its trimmed output if it succeeded and printed something
+fn git(dir: &Path, args: &[&str]) -> Option<String> {
+    let output = std::process::Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+
+    let stdout = String::from_utf8(output.stdout).ok()?;
+    let stdout = stdout.trim();
+    (!stdout.is_empty()).then(|| stdout.to_owned())
+}
+
+/// Directory of a flake reference that nix fetches with git, like `.` or `git+file:///etc/nixos`.
+/// `path:` flakes copy untracked files too, so they are left out.
+pub fn local_path(reference: &str) -> Option<PathBuf> {
+    let path = reference.split('?').next()?;
+    let path = ["git+file://", "git+file:"]
+        .iter()
+        .find_map(|prefix| path.strip_prefix(prefix))
+        .unwrap_or(path);
+
+    (path.starts_with('/') || path.starts_with('.')).then(|| PathBuf::from(path))
+}
+
+#[test]
+fn test_local_path() {
+    assert_eq!(local_path("."), Some(PathBuf::from(".")));
+    assert_eq!(local_path("/etc/nixos"), Some(PathBuf::from("/etc/nixos")));
+    assert_eq!(
+        local_path("git+file:///etc/nixos?ref=main"),
+        Some(PathBuf::from("/etc/nixos"))
+    );
+    assert_eq!(local_path("path:./config"), None);
+    assert_eq!(local_path("path:/etc/nixos"), None);
+    assert_eq!(local_path("github:nixos/nixpkgs"), None);
+    assert_eq!(local_path("nixpkgs"), None);
+}
@@ -0,0 +1,84 @@
+use std::time::{Duration, Instant};
+
+use color_eyre::eyre::bail;
+use tracing::{info, warn};
+
+use crate::commands::Command;
+use crate::Result;
+
+// Time between attempts, while the checks keep failing
+const RETRY_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Checks that must pass after activating a configuration
+#[derive(Debug)]
+pub struct HealthChecks<'a> {
+    pub commands: &'a [String],
+    pub system_running: bool,
+    pub timeout: Duration,
+}
+
+impl HealthChecks<'_> {
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty() && !self.system_running
+    }
+
+    /// Retry the checks on `host` until they all pass, failing once the timeout runs out
+    pub fn run(&self, host: Option<&str>) -> Result<()> {
+        info!("Running health checks");
+
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let err = match self.run_once(host, remaining) {
+                Ok(()) => {
+                    info!("Health checks passed");
+                    return Ok(());
+                }
+                Err(err) => err,
+            };
+
+            if Instant::now() + RETRY_INTERVAL >= deadline {
+                return Err(err.wrap_err(format!(
+                    "Health checks didn't pass within {}",
+                    humantime::format_duration(self.timeout)
+                )));
+            }
+
+            warn!("{err}, retrying");
+            std::thread::sleep(RETRY_INTERVAL);
+        }
+    }
+
+    fn run_once(&self, host: Option<&str>, remaining: Duration) -> Result<()> {
+        // Bound every check, so that a hanging one can't outlive the deadline
+        let timeout = remaining.as_secs().max(1).to_string();
+
+        if self.system_running {
+            let state = Command::new("timeout")
+                .arg(&timeout)
+                .args(["systemctl", "is-system-running", "--wait"])
+                .ssh(host.map(str::to_owned))
+                .run_capture()?
+                .unwrap_or_default();
+
+            match state.trim() {
+                "running" => (),
+                "" => bail!("Couldn't get the state of the system"),
+                state => bail!("The system is {state}"),
+            }
+        }
+
+        for check in self.commands {
+            if let Err(err) = Command::new("timeout")
+                .arg(&timeout)
+                .args(["sh", "-c", check])
+                .ssh(host.map(str::to_owned))
+                .run()
+            {
+                bail!("Health check `{check}` failed: {err}");
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -1,3 +1,4 @@
+use std::collections::BTreeMap;
 use std::env;
 use std::path::PathBuf;
 
@@ -5,11 +6,20 @@ use color_eyre::eyre::bail;
 use color_eyre::Result;
 use tracing::{debug, info, warn};
 
+use crate::audit;
 use crate::commands;
 use crate::commands::Command;
+use crate::diff;
+use crate::generations;
+use crate::git;
+use crate::hooks::{self, Hooks, Phase};
 use crate::installable::Installable;
-use crate::interface::{self, HomeRebuildArgs, HomeReplArgs, HomeSubcommand};
+use crate::interface::{
+    self, HomeGenerationsArgs, HomeRebuildArgs, HomeReplArgs, HomeSubcommand, InfoFormat,
+};
+use crate::specialisation;
 use crate::update::update;
+use crate::util::parallel_map;
 
 impl interface::HomeArgs {
     pub fn run(self) -> Result<()> {
@@ -23,10 +33,22 @@ impl interface::HomeArgs {
                 args.rebuild(Build)
             }
             HomeSubcommand::Repl(args) => args.run(),
+            HomeSubcommand::Info(args) => args.info(),
         }
     }
 }
 
+/// Locations of the home-manager profile, the legacy per-user one first
+fn profiles() -> [PathBuf; 2] {
+    [
+        PathBuf::from("/nix/var/nix/profiles/per-user")
+            .join(env::var("USER").expect("Couldn't get username"))
+            .join("home-manager"),
+        PathBuf::from(env::var("HOME").expect("Couldn't get home directory"))
+            .join(".local/state/nix/profiles/home-manager"),
+    ]
+}
+
 #[derive(Debug)]
 enum HomeRebuildVariant {
     Build,
@@ -35,9 +57,34 @@ enum HomeRebuildVariant {
 
 impl HomeRebuildArgs {
     fn rebuild(self, variant: HomeRebuildVariant) -> Result<()> {
+        self.common.installable.reject_channel();
+
+        let name = match variant {
+            HomeRebuildVariant::Build => "build",
+            HomeRebuildVariant::Switch => "switch",
+        };
+        let mut audit = audit::Entry::new("home", name);
+        let result = hooks::wrap(name, |hooks| {
+            self.rebuild_with_hooks(variant, hooks, &mut audit)
+        });
+        audit.finish(&result);
+        result
+    }
+
+    fn rebuild_with_hooks(
+        self,
+        variant: HomeRebuildVariant,
+        hooks: &mut Hooks,
+        audit: &mut audit::Entry,
+    ) -> Result<()> {
         use HomeRebuildVariant::*;
 
+        if let Ok(hostname) = hostname::get() {
+            hooks.hostname(&hostname.to_string_lossy());
+        }
+
         if self.update_args.update {
+            hooks.run(Phase::PreUpdate)?;
             update(&self.common.installable, self.update_args.update_input)?;
         }
 
@@ -51,25 +98,24 @@ impl HomeRebuildArgs {
 
         debug!(?out_path);
 
+        git::check_untracked(&self.common.installable, self.common.add_intent)?;
+
         let toplevel = toplevel_for(self.common.installable.clone(), true, &self.extra_args)?;
 
+        hooks.run(Phase::PreBuild)?;
+
         commands::Build::new(toplevel)
-            .extra_arg("--out-link")
-            .extra_arg(out_path.get_path())
+            .out_link(out_path.get_path())
+            .build_host(self.common.build_host.clone())
             .extra_args(&self.extra_args)
             .message("Building Home-Manager configuration")
             .nom(!self.common.no_nom)
             .run()?;
 
-        let prev_generation: Option<PathBuf> = [
-            PathBuf::from("/nix/var/nix/profiles/per-user")
-                .join(env::var("USER").expect("Couldn't get username"))
-                .join("home-manager"),
-            PathBuf::from(env::var("HOME").expect("Couldn't get home directory"))
-                .join(".local/state/nix/profiles/home-manager"),
-        ]
-        .into_iter()
-        .find(|next| next.exists());
+        hooks.out_path(out_path.get_path());
+        hooks.run(Phase::PostBuild)?;
+
+        let prev_generation: Option<PathBuf> = profiles().into_iter().find(|next| next.exists());
 
         debug!(?prev_generation);
 
@@ -78,27 +124,24 @@ impl HomeRebuildArgs {
 
         let current_specialisation = std::fs::read_to_string(spec_location.to_str().unwrap()).ok();
 
-        let target_specialisation = if self.no_specialisation {
-            None
-        } else {
-            current_specialisation.or(self.specialisation)
-        };
+        let target_specialisation = specialisation::resolve(
+            out_path.get_path(),
+            self.specialisation.as_deref(),
+            current_specialisation.as_deref(),
+            self.no_specialisation,
+        )?;
 
         debug!("target_specialisation: {target_specialisation:?}");
 
         let target_profile = match &target_specialisation {
-            None => out_path,
-            Some(spec) => Box::new(out_path.get_path().join("specialisation").join(spec)),
+            None => out_path.get_path().to_owned(),
+            Some(spec) => out_path.get_path().join("specialisation").join(spec),
         };
 
         // just do nothing for None case (fresh installs)
-        if let Some(generation) = prev_generation {
-            Command::new("nvd")
-                .arg("diff")
-                .arg(generation)
-                .arg(target_profile.get_path())
-                .message("Comparing changes")
-                .run()?;
+        if let Some(generation) = &prev_generation {
+            let changes = diff::print_diff(generation, &target_profile)?;
+            audit.changes = Some(changes.summary());
         }
 
         if self.common.dry || matches!(variant, Build) {
@@ -117,18 +160,28 @@ impl HomeRebuildArgs {
             }
         }
 
+        hooks.run(Phase::PreActivate)?;
+
+        audit.installable(&self.common.installable);
+        audit.out_path = Some(target_profile.clone());
+        audit.start(audit::Scope::User);
+
         if let Some(ext) = &self.backup_extension {
             info!("Using {} as the backup extension", ext);
             env::set_var("HOME_MANAGER_BACKUP_EXT", ext);
         }
 
-        Command::new(target_profile.get_path().join("activate"))
+        Command::new(target_profile.join("activate"))
             .message("Activating configuration")
             .run()?;
 
+        audit.generation = prev_generation.and_then(|profile| generations::current(&profile).ok());
+
+        hooks.run(Phase::PostActivate)?;
+
         // Make sure out_path is not accidentally dropped
         // https://docs.rs/tempfile/3.12.0/tempfile/index.html#early-drop-pitfall
-        drop(target_profile);
+        drop(out_path);
 
         Ok(())
     }
@@ -243,14 +296,85 @@ where
                 attribute.extend(toplevel);
             }
         }
-        Installable::Store { .. } => {}
+        Installable::Channel { .. } | Installable::Store { .. } => {}
     }
 
     Ok(res)
 }
 
+impl HomeGenerationsArgs {
+    fn info(&self) -> Result<()> {
+        let profiles: Vec<PathBuf> = profiles()
+            .into_iter()
+            .filter(|profile| profile.is_symlink())
+            .collect();
+        if profiles.is_empty() {
+            bail!("No home-manager profile found");
+        }
+
+        let jobs = std::thread::available_parallelism().map_or(4, |n| n.get());
+        let mut descriptions = Vec::new();
+        for profile in &profiles {
+            let generation_dirs = generations::list(profile)?;
+            let mut profile_descriptions: Vec<generations::HomeGenerationInfo> = generation_dirs
+                .iter()
+                .filter_map(|(_, gen_dir)| generations::describe_home(gen_dir, profile))
+                .collect();
+
+            let sizes: BTreeMap<u64, u64> =
+                parallel_map(&generation_dirs, jobs, |(number, dir)| {
+                    diff::closure_size(dir).ok().map(|size| (*number, size))
+                })
+                .into_iter()
+                .flatten()
+                .collect();
+
+            for description in profile_descriptions.iter_mut() {
+                description.closure_size = sizes.get(&description.number).copied();
+            }
+
+            descriptions.push((profile, profile_descriptions));
+        }
+
+        let format = if self.json {
+            InfoFormat::Json
+        } else {
+            self.format
+        };
+
+        match format {
+            InfoFormat::Table => {
+                for (i, (profile, generations)) in descriptions.iter().enumerate() {
+                    if i > 0 {
+                        println!();
+                    }
+                    generations::print_home_info(profile, generations);
+                }
+            }
+            InfoFormat::Json => {
+                let all: Vec<_> = descriptions
+                    .iter()
+                    .flat_map(|(_, generations)| generations)
+                    .collect();
+                println!("{}", serde_json::to_string_pretty(&all)?);
+            }
+            InfoFormat::Csv => {
+                let all: Vec<_> = descriptions
+                    .into_iter()
+                    .flat_map(|(_, generations)| generations)
+                    .collect();
+                generations::print_home_csv(&all);
+            }
+        }
+
+        Ok(())
+    }
+}
+
 impl HomeReplArgs {
     fn run(self) -> Result<()> {
+        self.installable.reject_channel();
+
         let toplevel = toplevel_for(self.installable, false, &self.extra_args)?;
 
         Command::new("nix")
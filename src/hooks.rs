@@ -0,0 +1,211 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::{Context, Report};
+use tracing::{debug, warn};
+
+use crate::commands::Command;
+use crate::Result;
+
+/// Points of a rebuild where user hooks run
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Phase {
+    PreUpdate,
+    PreBuild,
+    PostBuild,
+    PreActivate,
+    PostActivate,
+    OnFailure,
+}
+
+impl Phase {
+    const ALL: [Phase; 6] = [
+        Phase::PreUpdate,
+        Phase::PreBuild,
+        Phase::PostBuild,
+        Phase::PreActivate,
+        Phase::PostActivate,
+        Phase::OnFailure,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Phase::PreUpdate => "pre-update",
+            Phase::PreBuild => "pre-build",
+            Phase::PostBuild => "post-build",
+            Phase::PreActivate => "pre-activate",
+            Phase::PostActivate => "post-activate",
+            Phase::OnFailure => "on-failure",
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Hook {
+    /// Shell command from the config file
+    Shell(String),
+    /// Executable from NH_HOOKS_DIR
+    Executable(PathBuf),
+}
+
+/// Hooks for a rebuild, along with the context they receive as environment variables
+#[derive(Debug, Default)]
+pub struct Hooks {
+    hooks: BTreeMap<Phase, Vec<Hook>>,
+    env: Vec<(&'static str, String)>,
+}
+
+/// Load the hooks and run `f` with them, running the on-failure hooks if it fails
+pub fn wrap<T, F>(variant: &str, f: F) -> Result<T>
+where
+    F: FnOnce(&mut Hooks) -> Result<T>,
+{
+    let mut hooks = Hooks::load()?;
+    hooks.set("NH_VARIANT", variant);
+
+    let result = f(&mut hooks);
+    if let Err(err) = &result {
+        hooks.on_failure(err);
+    }
+    result
+}
+
+impl Hooks {
+    /// Hooks from `$XDG_CONFIG_HOME/nh/hooks.json`, followed by the ones in `NH_HOOKS_DIR`
+    pub fn load() -> Result<Self> {
+        let mut hooks = Self::default();
+
+        if let Some(config) = config_file().filter(|path| path.exists()) {
+            hooks.load_config(&config)?;
+        }
+
+        if let Some(dir) = std::env::var_os("NH_HOOKS_DIR") {
+            hooks.load_dir(Path::new(&dir))?;
+        }
+
+        debug!(?hooks);
+        Ok(hooks)
+    }
+
+    fn load_config(&mut self, path: &Path) -> Result<()> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Reading hooks config {}", path.display()))?;
+        let config: BTreeMap<String, Vec<String>> = serde_json::from_str(&contents)
+            .with_context(|| format!("Parsing hooks config {}", path.display()))?;
+
+        for (name, commands) in config {
+            match Phase::ALL.into_iter().find(|phase| phase.name() == name) {
+                Some(phase) => self
+                    .hooks
+                    .entry(phase)
+                    .or_default()
+                    .extend(commands.into_iter().map(Hook::Shell)),
+                None => warn!("Ignoring hooks for unknown phase `{name}`"),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Each phase runs `<dir>/<phase>` and then every file in `<dir>/<phase>.d`, sorted
+    fn load_dir(&mut self, dir: &Path) -> Result<()> {
+        for phase in Phase::ALL {
+            let hooks = self.hooks.entry(phase).or_default();
+
+            let single = dir.join(phase.name());
+            if single.is_file() {
+                hooks.push(Hook::Executable(single));
+            }
+
+            let dir = dir.join(format!("{}.d", phase.name()));
+            if dir.is_dir() {
+                let mut entries = fs::read_dir(&dir)
+                    .with_context(|| format!("Reading hooks directory {}", dir.display()))?
+                    .filter_map(|entry| entry.ok().map(|e| e.path()))
+                    .filter(|path| path.is_file())
+                    .collect::<Vec<_>>();
+                entries.sort();
+                hooks.extend(entries.into_iter().map(Hook::Executable));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Pass a variable to every hook that runs after this
+    pub fn set<V: AsRef<str>>(&mut self, key: &'static str, value: V) {
+        self.env.retain(|(k, _)| *k != key);
+        self.env.push((key, value.as_ref().to_owned()));
+    }
+
+    pub fn hostname(&mut self, hostname: &str) {
+        self.set("NH_HOSTNAME", hostname);
+    }
+
+    pub fn out_path(&mut self, out_path: &Path) {
+        self.set("NH_OUT_PATH", out_path.to_string_lossy());
+    }
+
+    /// Run the hooks of a phase, failing on the first one that exits non-zero
+    pub fn run(&self, phase: Phase) -> Result<()> {
+        for hook in self.hooks.get(&phase).into_iter().flatten() {
+            let (cmd, name) = match hook {
+                Hook::Shell(script) => (Command::new("sh").arg("-c").arg(script), script.clone()),
+                Hook::Executable(path) => (Command::new(path), path.display().to_string()),
+            };
+
+            self.env
+                .iter()
+                .fold(cmd, |cmd, (key, value)| cmd.env(key, value))
+                .env("NH_HOOK_PHASE", phase.name())
+                .message(format!("Running {} hook: {name}", phase.name()))
+                .run()
+                .with_context(|| format!("The {} hook `{name}` failed", phase.name()))?;
+        }
+
+        Ok(())
+    }
+
+    fn on_failure(&mut self, err: &Report) {
+        self.set("NH_ERROR", err.to_string());
+        if let Err(err) = self.run(Phase::OnFailure) {
+            warn!("{err:?}");
+        }
+    }
+}
+
+fn config_file() -> Option<PathBuf> {
+    let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .filter(|path| path.is_absolute())
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+
+    Some(config_dir.join("nh").join("hooks.json"))
+}
+
+#[test]
+fn test_load_dir() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("pre-build"), "").unwrap();
+    fs::create_dir(dir.path().join("post-activate.d")).unwrap();
+    fs::write(dir.path().join("post-activate.d/20-notify"), "").unwrap();
+    fs::write(dir.path().join("post-activate.d/10-snapshot"), "").unwrap();
+
+    let mut hooks = Hooks::default();
+    hooks.load_dir(dir.path()).unwrap();
+
+    let names = |phase| -> Vec<_> {
+        hooks.hooks[&phase]
+            .iter()
+            .map(|hook| match hook {
+                Hook::Executable(path) => path.file_name().unwrap().to_owned(),
+                Hook::Shell(_) => unreachable!(),
+            })
+            .collect()
+    };
+
+    assert_eq!(names(Phase::PreBuild), ["pre-build"]);
+    assert_eq!(names(Phase::PostActivate), ["10-snapshot", "20-notify"]);
+    assert!(names(Phase::OnFailure).is_empty());
+}
@@ -0,0 +1,309 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::IsTerminal;
+use std::path::Path;
+
+use color_eyre::eyre::{bail, Context, ContextCompat};
+use owo_colors::OwoColorize;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+use crate::commands::{self, Command};
+use crate::diff::{Closure, ClosureDiff};
+use crate::git;
+use crate::installable::Installable;
+use crate::interface::{HostsArgs, OsRebuildArgs};
+use crate::nixos::toplevel_for;
+use crate::util::{format_size, parallel_map};
+use crate::Result;
+
+// Evaluates each attribute separately, so that a broken host doesn't hide the others
+const DESCRIBE_HOSTS: &str = r#"
+configs: builtins.mapAttrs (name: config:
+  let try = x: let res = builtins.tryEval x; in if res.success then res.value else null; in {
+    system = try config.pkgs.stdenv.hostPlatform.system;
+    hostName = try config.config.networking.hostName;
+  }) configs
+"#;
+
+/// A configuration of a flake, like `nixosConfigurations.<name>`
+#[derive(Debug, Serialize)]
+pub struct Host {
+    pub name: String,
+    pub system: Option<String>,
+    #[serde(rename = "hostName")]
+    pub hostname: Option<String>,
+}
+
+/// Flake reference of an installable that doesn't select an attribute itself
+fn flake_reference(installable: &Installable) -> Option<&str> {
+    match installable {
+        Installable::Flake {
+            reference,
+            attribute,
+        } if attribute.is_empty() => Some(reference),
+        _ => None,
+    }
+}
+
+fn eval(reference: &str, configurations: &str, apply: &str) -> Result<String> {
+    Command::new("nix")
+        .args(["eval", "--json", "--apply", apply])
+        .arg(format!("{reference}#{configurations}"))
+        .run_capture()?
+        .filter(|output| !output.trim().is_empty())
+        .with_context(|| format!("Evaluating {configurations} of {reference}"))
+}
+
+/// Names of the configurations in a flake
+pub fn names(reference: &str, configurations: &str) -> Result<Vec<String>> {
+    let output = eval(reference, configurations, "builtins.attrNames")?;
+    Ok(serde_json::from_str(&output)?)
+}
+
+/// Configurations in a flake, along with their system and hostname
+pub fn list(reference: &str, configurations: &str) -> Result<Vec<Host>> {
+    #[derive(Deserialize)]
+    struct Description {
+        system: Option<String>,
+        #[serde(rename = "hostName")]
+        hostname: Option<String>,
+    }
+
+    let output = eval(reference, configurations, DESCRIBE_HOSTS)?;
+    let hosts: BTreeMap<String, Description> = serde_json::from_str(&output)?;
+
+    Ok(hosts
+        .into_iter()
+        .map(|(name, description)| Host {
+            name,
+            system: description.system,
+            hostname: description.hostname,
+        })
+        .collect())
+}
+
+/// Run `f` with the configuration for `hostname`. If it fails because the flake has no such
+/// configuration, offer to pick another one on a terminal and run `f` again with it. Returns the
+/// hostname that was used.
+pub fn with_configuration<T>(
+    installable: &Installable,
+    configurations: &str,
+    hostname: String,
+    mut f: impl FnMut(&str) -> Result<T>,
+) -> Result<(String, T)> {
+    let err = match f(&hostname) {
+        Ok(value) => return Ok((hostname, value)),
+        Err(err) => err,
+    };
+
+    // Only listed after a failure, so that working runs don't pay for another evaluation
+    let Some(reference) = flake_reference(installable) else {
+        return Err(err);
+    };
+    let names = match names(reference, configurations) {
+        Ok(names) => names,
+        Err(names_err) => {
+            debug!(?names_err, "Couldn't list {configurations}");
+            return Err(err);
+        }
+    };
+
+    if names.contains(&hostname) {
+        return Err(err);
+    }
+
+    if names.is_empty() {
+        return Err(err.wrap_err(format!("The flake {reference} has no {configurations}")));
+    }
+
+    if !std::io::stdin().is_terminal() {
+        return Err(err.wrap_err(format!(
+            "No configuration `{hostname}` in {reference}#{configurations}, available ones: {}",
+            names.join(", ")
+        )));
+    }
+
+    warn!("No configuration `{hostname}` in {reference}#{configurations}");
+
+    let selection = dialoguer::FuzzySelect::new()
+        .with_prompt("Select a configuration")
+        .items(&names)
+        .default(0)
+        .interact_opt()?;
+
+    let Some(index) = selection else {
+        bail!("No configuration selected");
+    };
+
+    let hostname = names[index].clone();
+    let value = f(&hostname)?;
+    Ok((hostname, value))
+}
+
+impl HostsArgs {
+    pub fn hosts(&self, configurations: &str) -> Result<()> {
+        let Some(reference) = flake_reference(&self.installable) else {
+            bail!("Listing hosts requires a flake installable, without an attribute");
+        };
+
+        let hosts = list(reference, configurations)?;
+
+        if self.json {
+            println!("{}", serde_json::to_string_pretty(&hosts)?);
+            return Ok(());
+        }
+
+        let width = hosts
+            .iter()
+            .map(|host| host.name.len())
+            .max()
+            .unwrap_or_default()
+            .max(4);
+        let local = hostname::get()
+            .ok()
+            .and_then(|name| name.into_string().ok());
+
+        println!(
+            "{}",
+            format!("{:<width$}  {:<16}  Hostname", "Name", "System").bold()
+        );
+        for host in &hosts {
+            let name = format!("{:<width$}", host.name);
+            let name = if local.as_ref() == Some(&host.name) {
+                name.green().to_string()
+            } else {
+                name
+            };
+
+            println!(
+                "{name}  {:<16}  {}",
+                host.system.as_deref().unwrap_or("-"),
+                host.hostname.as_deref().unwrap_or("-"),
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// Outcome of building one host with --all-hosts
+struct HostBuild {
+    name: String,
+    result: Result<(u64, Option<String>)>,
+}
+
+impl OsRebuildArgs {
+    /// Build the toplevel of every nixosConfigurations host, `jobs` at a time
+    pub fn build_all_hosts(&self) -> Result<()> {
+        let Some(reference) = flake_reference(&self.common.installable) else {
+            bail!("--all-hosts requires a flake installable, without an attribute");
+        };
+
+        git::check_untracked(&self.common.installable, self.common.add_intent)?;
+
+        let names = names(reference, "nixosConfigurations")?;
+        if names.is_empty() {
+            bail!("The flake {reference} has no nixosConfigurations");
+        }
+
+        // Each host gets its own link, so that the next run can diff against it
+        let (links_dir, _tempdir) = match &self.common.out_link {
+            Some(dir) => {
+                fs::create_dir_all(dir)
+                    .with_context(|| format!("Creating out-link directory {}", dir.display()))?;
+                (dir.clone(), None)
+            }
+            None => {
+                let dir = tempfile::Builder::new().prefix("nh-os").tempdir()?;
+                (dir.path().to_owned(), Some(dir))
+            }
+        };
+
+        let jobs = self.jobs.clamp(1, names.len());
+        info!("Building {} hosts, {jobs} at a time", names.len());
+
+        let results = parallel_map(&names, jobs, |name| {
+            let result = self.build_host(name, &links_dir.join(name), jobs == 1);
+            if let Err(err) = &result {
+                warn!("Failed to build {name}: {err}");
+            }
+
+            HostBuild {
+                name: name.clone(),
+                result,
+            }
+        });
+
+        print_builds(&results);
+
+        let failed = results.iter().filter(|build| build.result.is_err()).count();
+        if failed > 0 {
+            bail!("{failed} of {} hosts failed to build", results.len());
+        }
+
+        Ok(())
+    }
+
+    /// Build a host into `out_link`, returning its closure size and the changes since the
+    /// previous build at that link
+    fn build_host(&self, name: &str, out_link: &Path, nom: bool) -> Result<(u64, Option<String>)> {
+        let previous = out_link.canonicalize().ok();
+
+        let toplevel = toplevel_for(name, self.common.installable.clone(), "toplevel");
+        commands::Build::new(toplevel)
+            .out_link(out_link)
+            .build_host(self.common.build_host.clone())
+            .extra_args(&self.extra_args)
+            .message(format!("Building {name}"))
+            .nom(nom && !self.common.no_nom)
+            .run()?;
+
+        let closure = Closure::query(out_link, None)?;
+        let changes = match previous {
+            Some(previous) => {
+                let previous = Closure::query(&previous, None)?;
+                Some(ClosureDiff::new(&previous, &closure).summary())
+            }
+            None => None,
+        };
+
+        Ok((closure.size(), changes))
+    }
+}
+
+fn print_builds(builds: &[HostBuild]) {
+    let width = builds
+        .iter()
+        .map(|build| build.name.len())
+        .max()
+        .unwrap_or_default()
+        .max(4);
+
+    println!(
+        "{}",
+        format!(
+            "{:<width$}  {:<6}  {:>12}  Changes",
+            "Host", "Result", "Closure Size"
+        )
+        .bold()
+    );
+
+    for build in builds {
+        match &build.result {
+            Ok((size, changes)) => println!(
+                "{:<width$}  {:<6}  {:>12}  {}",
+                build.name,
+                "ok".green(),
+                format_size(*size),
+                changes.as_deref().unwrap_or("-"),
+            ),
+            Err(_) => println!(
+                "{:<width$}  {:<6}  {:>12}  -",
+                build.name,
+                "failed".red(),
+                "-"
+            ),
+        }
+    }
+}
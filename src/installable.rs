@@ -1,12 +1,15 @@
-use std::path::PathBuf;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
 use std::{env, fs};
 
 use clap::error::ErrorKind;
-use clap::{Arg, ArgAction, Args, FromArgMatches};
+use clap::{Arg, ArgAction, Args, CommandFactory, FromArgMatches};
 use color_eyre::owo_colors::OwoColorize;
 
 // Reference: https://nix.dev/manual/nix/2.18/command-ref/new-cli/nix
 
+const DEFAULT_NIXOS_CONFIG: &str = "/etc/nixos/configuration.nix";
+
 #[derive(Debug, Clone)]
 pub enum Installable {
     Flake {
@@ -24,6 +27,11 @@ pub enum Installable {
         expression: String,
         attribute: Vec<String>,
     },
+    /// `<nixpkgs/nixos>` from the channels, for systems that don't use flakes
+    Channel {
+        configuration: PathBuf,
+        attribute: Vec<String>,
+    },
 }
 
 impl FromArgMatches for Installable {
@@ -87,6 +95,13 @@ impl FromArgMatches for Installable {
             });
         }
 
+        if let Some(configuration) = channel_configuration() {
+            return Ok(Self::Channel {
+                configuration,
+                attribute: Vec::new(),
+            });
+        }
+
         Err(clap::Error::new(ErrorKind::TooFewValues))
     }
 
@@ -135,6 +150,10 @@ Nix accepts various kinds of installables:
 
 [PATH]
     Path or symlink to a /nix/store path
+
+Without any of the above, <nixpkgs/nixos> is built from the channels,
+with nixos-config set to the file in NIXOS_CONFIG or /etc/nixos/configuration.nix.
+/etc/nixos/configuration.nix isn't used when /etc/nixos/flake.nix exists.
 "#,
                     env::var("NH_FLAKE").unwrap_or_default(),
                     "-f".yellow(),
@@ -152,6 +171,53 @@ Nix accepts various kinds of installables:
     }
 }
 
+/// The NixOS configuration of a system that uses channels, if there is one
+fn channel_configuration() -> Option<PathBuf> {
+    resolve_channel_configuration(env::var_os("NIXOS_CONFIG"), Path::new(DEFAULT_NIXOS_CONFIG))
+}
+
+// A flake next to the default configuration is what nixos-rebuild would build, so the channels
+// aren't used in its place
+fn resolve_channel_configuration(
+    nixos_config: Option<OsString>,
+    default: &Path,
+) -> Option<PathBuf> {
+    match nixos_config {
+        Some(path) if !path.is_empty() => Some(PathBuf::from(path)),
+        _ => Some(default.to_owned())
+            .filter(|path| path.exists() && !path.with_file_name("flake.nix").exists()),
+    }
+}
+
+#[test]
+fn test_channel_configuration() {
+    let dir = tempfile::tempdir().unwrap();
+    let default = dir.path().join("configuration.nix");
+
+    assert_eq!(
+        resolve_channel_configuration(Some(OsString::from("/srv/nixos.nix")), &default),
+        Some(PathBuf::from("/srv/nixos.nix"))
+    );
+    assert_eq!(resolve_channel_configuration(None, &default), None);
+
+    fs::write(&default, "{ }").unwrap();
+    assert_eq!(
+        resolve_channel_configuration(Some(OsString::new()), &default),
+        Some(default.clone())
+    );
+    assert_eq!(
+        resolve_channel_configuration(None, &default),
+        Some(default.clone())
+    );
+
+    fs::write(dir.path().join("flake.nix"), "{ }").unwrap();
+    assert_eq!(resolve_channel_configuration(None, &default), None);
+    assert_eq!(
+        resolve_channel_configuration(Some(OsString::from("/srv/nixos.nix")), &default),
+        Some(PathBuf::from("/srv/nixos.nix"))
+    );
+}
+
 // TODO: should handle quoted attributes, like foo."bar.baz" -> ["foo", "bar.baz"]
 // maybe use chumsky?
 fn parse_attribute<S>(s: S) -> Vec<String>
@@ -225,6 +291,16 @@ impl Installable {
                 res.push(expression.to_string());
                 res.push(join_attribute(attribute));
             }
+            Installable::Channel {
+                configuration,
+                attribute,
+            } => {
+                res.push(String::from("--file"));
+                res.push(String::from("<nixpkgs/nixos>"));
+                res.push(String::from("-I"));
+                res.push(format!("nixos-config={}", configuration.display()));
+                res.push(join_attribute(attribute));
+            }
             Installable::Store { path } => res.push(path.to_str().unwrap().to_string()),
         }
 
@@ -251,6 +327,21 @@ fn test_installable_to_args() {
         .to_args(),
         vec!["--file", "w", r#"x."y.z""#]
     );
+
+    assert_eq!(
+        (Installable::Channel {
+            configuration: PathBuf::from("/etc/nixos/configuration.nix"),
+            attribute: vec![String::from("system")]
+        })
+        .to_args(),
+        vec![
+            "--file",
+            "<nixpkgs/nixos>",
+            "-I",
+            "nixos-config=/etc/nixos/configuration.nix",
+            "system"
+        ]
+    );
 }
 
 fn join_attribute<I>(attribute: I) -> String
@@ -286,12 +377,23 @@ fn test_join_attribute() {
 }
 
 impl Installable {
+    /// Exit with the usage error of a missing installable on the channel fallback, which
+    /// only `nh os` supports
+    pub fn reject_channel(&self) {
+        if let Installable::Channel { .. } = self {
+            clap::Error::new(ErrorKind::TooFewValues)
+                .with_cmd(&crate::interface::Main::command())
+                .exit();
+        }
+    }
+
     pub fn str_kind(&self) -> &str {
         match self {
             Installable::Flake { .. } => "flake",
             Installable::File { .. } => "file",
             Installable::Store { .. } => "store path",
             Installable::Expression { .. } => "expression",
+            Installable::Channel { .. } => "channel",
         }
     }
 }
@@ -48,6 +48,7 @@ pub enum NHCommand {
     Darwin(DarwinArgs),
     Search(SearchArgs),
     Clean(CleanProxy),
+    Log(LogArgs),
     #[command(hide = true)]
     Completions(CompletionArgs),
 }
@@ -61,6 +62,7 @@ impl NHCommand {
             NHCommand::Completions(args) => args.run(),
             NHCommand::Home(args) => args.run(),
             NHCommand::Darwin(args) => args.run(),
+            NHCommand::Log(args) => args.run(),
         }
     }
 }
@@ -89,11 +91,44 @@ pub enum OsSubcommand {
     /// Build the new configuration
     Build(OsRebuildArgs),
 
+    /// Build the new configuration and show which units activating it would change
+    DryActivate(OsRebuildArgs),
+
+    /// Build a QEMU virtual machine from the new configuration
+    BuildVm(OsBuildVmArgs),
+
+    /// Build a QEMU virtual machine from the new configuration, booting through a bootloader
+    BuildVmWithBootloader(OsBuildVmArgs),
+
     /// Load system in a repl
     Repl(OsReplArgs),
 
     /// List available generations from profile path
     Info(OsGenerationsArgs),
+
+    /// Rollback to a previous generation
+    Rollback(OsRollbackArgs),
+
+    /// Show the package changes between two generations or store paths
+    Diff(OsDiffArgs),
+
+    /// Delete specific generations, by number or range
+    Delete(OsDeleteArgs),
+
+    /// Pin a generation, so that nh clean never removes it
+    Pin(OsPinArgs),
+
+    /// Unpin a previously pinned generation
+    Unpin(OsUnpinArgs),
+
+    /// List or switch the specialisations of the running system
+    Specialisation(OsSpecialisationArgs),
+
+    /// Keep a configuration activated with --confirm-within, cancelling its revert
+    Confirm(OsConfirmArgs),
+
+    /// List the nixosConfigurations of a flake
+    Hosts(HostsArgs),
 }
 
 #[derive(Debug, Args)]
@@ -108,7 +143,7 @@ pub struct OsRebuildArgs {
     #[arg(long, short = 'H', global = true)]
     pub hostname: Option<String>,
 
-    /// Explicitely select some specialisation
+    /// Explicitly select some specialisation, instead of the currently running one
     #[arg(long, short)]
     pub specialisation: Option<String>,
 
@@ -123,6 +158,84 @@ pub struct OsRebuildArgs {
     /// Don't panic if calling nh as root
     #[arg(short = 'R', long, env = "NH_BYPASS_ROOT_CHECK")]
     pub bypass_root_check: bool,
+
+    /// Deploy the configuration to a remote host over ssh, like user@host
+    #[arg(long)]
+    pub target_host: Option<String>,
+
+    /// Reboot after activation if the kernel, initrd, kernel modules or systemd changed
+    #[arg(long)]
+    pub reboot_if_needed: bool,
+
+    /// Exit with this code instead of 0 when a reboot is required
+    #[arg(long, value_name = "CODE", conflicts_with = "reboot_if_needed")]
+    pub reboot_exit_code: Option<i32>,
+
+    /// Command that must succeed after activation, otherwise the previous generation is restored
+    #[arg(long = "health-check", value_name = "COMMAND")]
+    pub health_checks: Vec<String>,
+
+    /// Also require `systemctl is-system-running` to report a running system after activation
+    #[arg(long)]
+    pub check_system_running: bool,
+
+    /// How long health checks may keep failing before rolling back
+    #[arg(long, default_value = "2m")]
+    pub health_timeout: humantime::Duration,
+
+    /// Revert to the previous generation unless `nh os confirm` runs within this time after
+    /// activation and the health checks
+    #[arg(long, value_name = "DURATION")]
+    pub confirm_within: Option<humantime::Duration>,
+
+    /// Build every host in nixosConfigurations, with --out-link as a directory of links
+    #[arg(long, conflicts_with = "hostname")]
+    pub all_hosts: bool,
+
+    /// Number of hosts to build at the same time with --all-hosts
+    #[arg(long, short = 'j', default_value_t = 1, requires = "all_hosts")]
+    pub jobs: usize,
+
+    /// Use the system profile /nix/var/nix/profiles/system-profiles/NAME
+    #[arg(long, short = 'p', value_name = "NAME")]
+    pub profile_name: Option<String>,
+
+    /// Refuse to build a local git flake with uncommitted changes
+    #[arg(long)]
+    pub require_clean: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct OsBuildVmArgs {
+    #[command(flatten)]
+    pub common: OsRebuildArgs,
+
+    /// Run the virtual machine after building it
+    #[arg(long, short)]
+    pub run: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct OsRollbackArgs {
+    /// Only print actions, without performing them
+    #[arg(long, short = 'n')]
+    pub dry: bool,
+
+    /// Ask for confirmation
+    #[arg(long, short)]
+    pub ask: bool,
+
+    /// Rollback to this generation number instead of the previous one
+    #[arg(long, short)]
+    pub to: Option<u64>,
+
+    /// Use the system profile /nix/var/nix/profiles/system-profiles/NAME
+    #[arg(long, short = 'p', value_name = "NAME")]
+    pub profile_name: Option<String>,
+
+    /// Don't panic if calling nh as root
+    #[arg(short = 'R', long, env = "NH_BYPASS_ROOT_CHECK")]
+    pub bypass_root_check: bool,
 }
 
 #[derive(Debug, Args)]
@@ -145,6 +258,14 @@ pub struct CommonRebuildArgs {
     /// Path to save the result link, defaults to using a temporary directory
     #[arg(long, short)]
     pub out_link: Option<PathBuf>,
+
+    /// Realise the configuration on a remote host over ssh, like user@host
+    #[arg(long)]
+    pub build_host: Option<String>,
+
+    /// Run `git add -N` on untracked files of a local flake, so that nix can see them
+    #[arg(long)]
+    pub add_intent: bool,
 }
 
 #[derive(Debug, Args)]
@@ -162,6 +283,168 @@ pub struct OsGenerationsArgs {
     /// Path to Nix' profiles directory
     #[arg(long, short = 'P', default_value = "/nix/var/nix/profiles/system")]
     pub profile: Option<String>,
+
+    /// Output format
+    #[arg(long, value_enum, default_value_t = InfoFormat::Table)]
+    pub format: InfoFormat,
+
+    /// Output as JSON, shorthand for --format json
+    #[arg(long, conflicts_with = "format")]
+    pub json: bool,
+
+    /// Show the closure size history as a sparkline, with the table format
+    #[arg(long)]
+    pub sparkline: bool,
+
+    /// Use the system profile /nix/var/nix/profiles/system-profiles/NAME, instead of --profile
+    #[arg(long, short = 'p', value_name = "NAME", conflicts_with = "profile")]
+    pub profile_name: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum InfoFormat {
+    Table,
+    Json,
+    Csv,
+}
+
+#[derive(Debug, Args)]
+pub struct OsDiffArgs {
+    /// Generation number, `current`, `booted` or a store path to compare from
+    #[arg(default_value = "booted")]
+    pub from: String,
+
+    /// Generation number, `current`, `booted` or a store path to compare to
+    #[arg(default_value = "current")]
+    pub to: String,
+
+    /// Path to Nix' profiles directory, used to look up generation numbers
+    #[arg(long, short = 'P', default_value = "/nix/var/nix/profiles/system")]
+    pub profile: PathBuf,
+}
+
+#[derive(Debug, Args)]
+#[clap(verbatim_doc_comment)]
+/// Delete specific generations, by number or range
+///
+/// Generations can be selected with N, A..B, A.., ..B, current or booted, and excluded
+/// by prefixing them with `!`, like `nh os delete 120..125 !122`.
+/// Exclusions only narrow a selection, use `..` to start from every generation.
+/// The current and booted generations are never deleted.
+pub struct OsDeleteArgs {
+    /// Generations to delete or to exclude from deletion
+    #[arg(required = true)]
+    pub generations: Vec<String>,
+
+    /// Path to Nix' profiles directory
+    #[arg(long, short = 'P', default_value = "/nix/var/nix/profiles/system")]
+    pub profile: PathBuf,
+
+    /// Only print actions, without performing them
+    #[arg(long, short = 'n')]
+    pub dry: bool,
+
+    /// Ask for confirmation
+    #[arg(long, short)]
+    pub ask: bool,
+
+    /// Refresh the bootloader entries after deleting
+    #[arg(long, short)]
+    pub boot: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct OsPinArgs {
+    /// Generation number to pin
+    pub generation: u64,
+
+    /// Label to describe the pinned generation, like "known good"
+    #[arg(long, short)]
+    pub label: Option<String>,
+
+    /// Path to Nix' profiles directory
+    #[arg(long, short = 'P', default_value = "/nix/var/nix/profiles/system")]
+    pub profile: PathBuf,
+}
+
+#[derive(Debug, Args)]
+pub struct OsUnpinArgs {
+    /// Generation number to unpin
+    pub generation: u64,
+
+    /// Path to Nix' profiles directory
+    #[arg(long, short = 'P', default_value = "/nix/var/nix/profiles/system")]
+    pub profile: PathBuf,
+}
+
+#[derive(Debug, Args)]
+pub struct OsSpecialisationArgs {
+    #[command(subcommand)]
+    pub subcommand: OsSpecialisationSubcommand,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum OsSpecialisationSubcommand {
+    /// List the specialisations of the running system
+    List,
+
+    /// Activate a specialisation of the running system, without rebuilding
+    Switch(OsSpecialisationSwitchArgs),
+}
+
+#[derive(Debug, Args)]
+pub struct OsSpecialisationSwitchArgs {
+    /// Name of the specialisation, or `base` for the configuration without specialisation
+    pub name: String,
+
+    /// Only print actions, without performing them
+    #[arg(long, short = 'n')]
+    pub dry: bool,
+
+    /// Ask for confirmation
+    #[arg(long, short)]
+    pub ask: bool,
+
+    /// Don't panic if calling nh as root
+    #[arg(short = 'R', long, env = "NH_BYPASS_ROOT_CHECK")]
+    pub bypass_root_check: bool,
+}
+
+#[derive(Debug, Args)]
+/// Show the log of activations, rollbacks and cleanups
+pub struct LogArgs {
+    /// Only show entries of this hostname
+    #[arg(long)]
+    pub host: Option<String>,
+
+    /// Only show entries newer than this, like 1d or 2weeks
+    #[arg(long)]
+    pub since: Option<humantime::Duration>,
+
+    /// Output as JSON lines
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct HostsArgs {
+    #[command(flatten)]
+    pub installable: Installable,
+
+    /// Output as JSON
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct OsConfirmArgs {
+    /// Confirm the activation on a remote host over ssh, like user@host
+    #[arg(long)]
+    pub target_host: Option<String>,
+
+    /// Don't panic if calling nh as root
+    #[arg(short = 'R', long, env = "NH_BYPASS_ROOT_CHECK")]
+    pub bypass_root_check: bool,
 }
 
 #[derive(Args, Debug)]
@@ -269,6 +552,20 @@ pub enum HomeSubcommand {
 
     /// Load a home-manager configuration in a Nix REPL
     Repl(HomeReplArgs),
+
+    /// List home-manager generations
+    Info(HomeGenerationsArgs),
+}
+
+#[derive(Debug, Args)]
+pub struct HomeGenerationsArgs {
+    /// Output format
+    #[arg(long, value_enum, default_value_t = InfoFormat::Table)]
+    pub format: InfoFormat,
+
+    /// Output as JSON, shorthand for --format json
+    #[arg(long, conflicts_with = "format")]
+    pub json: bool,
 }
 
 #[derive(Debug, Args)]
@@ -285,7 +582,7 @@ pub struct HomeRebuildArgs {
     #[arg(long, short)]
     pub configuration: Option<String>,
 
-    /// Explicitely select some specialisation
+    /// Explicitly select some specialisation, instead of the currently running one
     #[arg(long, short)]
     pub specialisation: Option<String>,
 
@@ -342,6 +639,8 @@ pub enum DarwinSubcommand {
     Build(DarwinRebuildArgs),
     /// Load a nix-darwin configuration in a Nix REPL
     Repl(DarwinReplArgs),
+    /// List the darwinConfigurations of a flake
+    Hosts(HostsArgs),
 }
 
 #[derive(Debug, Args)]
@@ -356,6 +655,10 @@ pub struct DarwinRebuildArgs {
     #[arg(long, short = 'H', global = true)]
     pub hostname: Option<String>,
 
+    /// Use the system profile /nix/var/nix/profiles/system-profiles/NAME
+    #[arg(long, short = 'p', value_name = "NAME")]
+    pub profile_name: Option<String>,
+
     /// Extra arguments passed to nix build
     #[arg(last = true)]
     pub extra_args: Vec<String>,
@@ -1,11 +1,13 @@
 use std::fmt::Display;
 
+#[allow(dead_code)]
 #[derive(Debug, Clone)]
 pub struct Value<'v> {
     pub inner: &'v serde_json::Value,
     get_stack: Vec<String>,
 }
 
+#[allow(dead_code)]
 #[derive(Debug)]
 pub struct Error {
     get_stack: Vec<String>,
@@ -28,6 +30,7 @@ impl Display for Error {
 
 impl std::error::Error for Error {}
 
+#[allow(dead_code)]
 impl<'v> Value<'v> {
     pub fn new(value: &'v serde_json::Value) -> Self {
         Self {
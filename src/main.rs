@@ -1,15 +1,26 @@
+mod activation;
+mod audit;
 mod clean;
 mod commands;
 mod completion;
+mod confirm;
 mod darwin;
+mod diff;
 mod generations;
+mod git;
+mod health;
 mod home;
+mod hooks;
+mod hosts;
 mod installable;
 mod interface;
 mod json;
 mod logging;
 mod nixos;
+mod pins;
+mod reboot;
 mod search;
+mod specialisation;
 mod update;
 mod util;
 
@@ -1,19 +1,37 @@
+use std::collections::BTreeMap;
 use std::fs;
+use std::io::IsTerminal;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
+use chrono::{Local, Utc};
 use color_eyre::eyre::{bail, Context};
-use color_eyre::eyre::{eyre, Result};
+use color_eyre::eyre::{eyre, Report, Result};
 use tracing::{debug, info, warn};
 
+use crate::activation;
+use crate::audit;
 use crate::commands;
 use crate::commands::Command;
+use crate::confirm::{self, PendingConfirmation};
+use crate::diff;
 use crate::generations;
+use crate::git::{self, GitState};
+use crate::health::HealthChecks;
+use crate::hooks::{self, Hooks, Phase};
+use crate::hosts;
 use crate::installable::Installable;
 use crate::interface::OsSubcommand::{self};
-use crate::interface::{self, OsGenerationsArgs, OsRebuildArgs, OsReplArgs};
+use crate::interface::{
+    self, InfoFormat, OsBuildVmArgs, OsConfirmArgs, OsDiffArgs, OsGenerationsArgs, OsRebuildArgs,
+    OsReplArgs, OsRollbackArgs, OsSpecialisationArgs, OsSpecialisationSubcommand,
+};
+use crate::pins;
+use crate::reboot;
+use crate::specialisation;
 use crate::update::update;
+use crate::util::parallel_map;
 
-const SYSTEM_PROFILE: &str = "/nix/var/nix/profiles/system";
 const CURRENT_PROFILE: &str = "/run/current-system";
 
 const SPEC_LOCATION: &str = "/etc/specialisation";
@@ -29,10 +47,29 @@ impl interface::OsArgs {
                 if args.common.ask || args.common.dry {
                     warn!("`--ask` and `--dry` have no effect for `nh os build`");
                 }
+                if args.all_hosts {
+                    return args.build_all_hosts();
+                }
                 args.rebuild(Build)
             }
+            OsSubcommand::DryActivate(args) => {
+                if args.common.ask || args.common.dry {
+                    warn!("`--ask` and `--dry` have no effect for `nh os dry-activate`");
+                }
+                args.rebuild(DryActivate)
+            }
+            OsSubcommand::BuildVm(args) => args.build_vm(false),
+            OsSubcommand::BuildVmWithBootloader(args) => args.build_vm(true),
             OsSubcommand::Repl(args) => args.run(),
             OsSubcommand::Info(args) => args.info(),
+            OsSubcommand::Rollback(args) => args.rollback(),
+            OsSubcommand::Diff(args) => args.diff(),
+            OsSubcommand::Delete(args) => args.run(),
+            OsSubcommand::Pin(args) => args.run(),
+            OsSubcommand::Unpin(args) => args.run(),
+            OsSubcommand::Specialisation(args) => args.run(),
+            OsSubcommand::Confirm(args) => args.confirm(),
+            OsSubcommand::Hosts(args) => args.hosts("nixosConfigurations"),
         }
     }
 }
@@ -43,24 +80,68 @@ enum OsRebuildVariant {
     Switch,
     Boot,
     Test,
+    DryActivate,
+    BuildVm { bootloader: bool, run: bool },
+}
+
+impl OsRebuildVariant {
+    fn name(&self) -> &'static str {
+        match self {
+            OsRebuildVariant::Build => "build",
+            OsRebuildVariant::Switch => "switch",
+            OsRebuildVariant::Boot => "boot",
+            OsRebuildVariant::Test => "test",
+            OsRebuildVariant::DryActivate => "dry-activate",
+            OsRebuildVariant::BuildVm { .. } => "build-vm",
+        }
+    }
+}
+
+impl OsBuildVmArgs {
+    fn build_vm(self, bootloader: bool) -> Result<()> {
+        if self.common.common.ask || self.common.common.dry {
+            warn!("`--ask` and `--dry` have no effect for `nh os build-vm`");
+        }
+        self.common.rebuild(OsRebuildVariant::BuildVm {
+            bootloader,
+            run: self.run,
+        })
+    }
 }
 
 impl OsRebuildArgs {
     fn rebuild(self, variant: OsRebuildVariant) -> Result<()> {
+        let mut audit = audit::Entry::new("os", variant.name());
+        let result = hooks::wrap(variant.name(), |hooks| {
+            self.rebuild_with_hooks(variant, hooks, &mut audit)
+        });
+        audit.finish(&result);
+
+        // Exit only once the activation is logged
+        if let Ok(Some(code)) = result {
+            std::process::exit(code);
+        }
+        result.map(|_| ())
+    }
+
+    /// Returns the exit code to use when a reboot is needed and --reboot-exit-code is set
+    fn rebuild_with_hooks(
+        self,
+        variant: OsRebuildVariant,
+        hooks: &mut Hooks,
+        audit: &mut audit::Entry,
+    ) -> Result<Option<i32>> {
         use OsRebuildVariant::*;
 
-        let elevate = if self.bypass_root_check {
-            warn!("Bypassing root check, now running nix as root");
-            false
-        } else {
-            if nix::unistd::Uid::effective().is_root() {
-                bail!("Don't run nh os as root. I will call sudo internally as needed");
-            }
-            true
-        };
+        let elevate = should_elevate(self.bypass_root_check)?;
+        let profile = generations::system_profile(self.profile_name.as_deref())?;
 
-        if self.update_args.update {
-            update(&self.common.installable, self.update_args.update_input)?;
+        if self.all_hosts {
+            bail!("--all-hosts only applies to nh os build");
+        }
+
+        if self.confirm_within.is_some() && !matches!(variant, Test | Switch) {
+            bail!("--confirm-within only applies to activating commands, like switch or test");
         }
 
         let hostname = match &self.hostname {
@@ -72,6 +153,16 @@ impl OsRebuildArgs {
                 .to_owned(),
         };
 
+        if self.update_args.update {
+            hooks.run(Phase::PreUpdate)?;
+            update(&self.common.installable, self.update_args.update_input)?;
+        }
+
+        git::check_untracked(&self.common.installable, self.common.add_intent)?;
+
+        hooks.hostname(&hostname);
+        audit.hostname = self.target_host.clone().unwrap_or_else(|| hostname.clone());
+
         let out_path: Box<dyn crate::util::MaybeTempPath> = match self.common.out_link {
             Some(ref p) => Box::new(p.clone()),
             None => Box::new({
@@ -82,45 +173,126 @@ impl OsRebuildArgs {
 
         debug!(?out_path);
 
-        let toplevel = toplevel_for(hostname, self.common.installable.clone());
+        let final_attr = match variant {
+            BuildVm {
+                bootloader: true, ..
+            } => "vmWithBootLoader",
+            BuildVm { .. } => "vm",
+            _ => "toplevel",
+        };
 
-        commands::Build::new(toplevel)
-            .extra_arg("--out-link")
-            .extra_arg(out_path.get_path())
-            .extra_args(&self.extra_args)
-            .message("Building NixOS configuration")
-            .nom(!self.common.no_nom)
-            .run()?;
+        let git_state = GitState::detect(&self.common.installable);
+        if let Some(git) = git_state.as_ref().filter(|git| git.dirty) {
+            if self.require_clean {
+                bail!("The flake has uncommitted changes on top of {}", git.commit);
+            }
+            warn!("The flake has uncommitted changes on top of {}", git.commit);
+        }
 
-        let current_specialisation = std::fs::read_to_string(SPEC_LOCATION).ok();
+        hooks.run(Phase::PreBuild)?;
 
-        let target_specialisation = if self.no_specialisation {
-            None
-        } else {
-            current_specialisation.or_else(|| self.specialisation.to_owned())
+        let (hostname, ()) = hosts::with_configuration(
+            &self.common.installable,
+            "nixosConfigurations",
+            hostname,
+            |hostname| {
+                let toplevel = toplevel_for(hostname, self.common.installable.clone(), final_attr);
+
+                commands::Build::new(toplevel)
+                    .out_link(out_path.get_path())
+                    .build_host(self.common.build_host.clone())
+                    .extra_args(&self.extra_args)
+                    .message(match variant {
+                        BuildVm { .. } => "Building NixOS virtual machine",
+                        _ => "Building NixOS configuration",
+                    })
+                    .nom(!self.common.no_nom)
+                    .run()
+            },
+        )?;
+        hooks.hostname(&hostname);
+        audit.hostname = self.target_host.clone().unwrap_or(hostname);
+
+        // Remote hosts only know about the store path, not about our local out-link
+        let toplevel_path = out_path
+            .get_path()
+            .canonicalize()
+            .context("Resolving the built configuration")?;
+
+        hooks.out_path(&toplevel_path);
+        hooks.run(Phase::PostBuild)?;
+
+        if let BuildVm { run, .. } = variant {
+            let script = vm_script(&toplevel_path)?;
+            info!(
+                "Done. The virtual machine can be started by running {}",
+                script.display()
+            );
+
+            if run {
+                Command::new(&script)
+                    .message("Running virtual machine")
+                    .run()?;
+            }
+
+            return Ok(None);
+        }
+
+        let current_specialisation = match &self.target_host {
+            Some(host) => remote_specialisation(host)?,
+            None => std::fs::read_to_string(SPEC_LOCATION).ok(),
         };
 
+        let target_specialisation = specialisation::resolve(
+            &toplevel_path,
+            self.specialisation.as_deref(),
+            current_specialisation.as_deref(),
+            self.no_specialisation,
+        )?;
+
         debug!("target_specialisation: {target_specialisation:?}");
 
         let target_profile = match &target_specialisation {
-            None => out_path.get_path().to_owned(),
-            Some(spec) => out_path.get_path().join("specialisation").join(spec),
+            None => toplevel_path.clone(),
+            Some(spec) => toplevel_path.join("specialisation").join(spec),
+        };
+
+        let (current_profile, current_store) = match &self.target_host {
+            Some(host) => (remote_current_system(host)?, Some(format!("ssh://{host}"))),
+            None => (PathBuf::from(CURRENT_PROFILE), None),
         };
 
-        target_profile.try_exists().context("Doesn't exist")?;
+        let changes = diff::print_diff_with_store(
+            &current_profile,
+            current_store.as_deref(),
+            &target_profile,
+        )?;
+        audit.changes = Some(changes.summary());
 
-        Command::new("nvd")
-            .arg("diff")
-            .arg(CURRENT_PROFILE)
-            .arg(&target_profile)
-            .message("Comparing changes")
-            .run()?;
+        // Show what activation would restart before asking, that's when it matters
+        let dry_activate = match variant {
+            DryActivate => true,
+            Test | Switch => self.common.ask && !self.common.dry,
+            _ => false,
+        };
+
+        if dry_activate {
+            if let Some(host) = &self.target_host {
+                copy_to_host(host, &toplevel_path)?;
+            }
+
+            activation::dry_activate(&target_profile, elevate, self.target_host.clone())?.print();
+        }
+
+        if matches!(variant, DryActivate) {
+            return Ok(None);
+        }
 
         if self.common.dry || matches!(variant, Build) {
             if self.common.ask {
                 warn!("--ask has no effect as dry run was requested");
             }
-            return Ok(());
+            return Ok(None);
         }
 
         if self.common.ask {
@@ -132,52 +304,633 @@ impl OsRebuildArgs {
             }
         }
 
-        if let Test | Switch = variant {
-            // !! Use the target profile aka spec-namespaced
-            let switch_to_configuration =
-                target_profile.join("bin").join("switch-to-configuration");
-            let switch_to_configuration = switch_to_configuration.to_str().unwrap();
+        let health_checks = HealthChecks {
+            commands: &self.health_checks,
+            system_running: self.check_system_running,
+            timeout: *self.health_timeout,
+        };
 
-            Command::new(switch_to_configuration)
-                .arg("test")
-                .message("Activating configuration")
-                .elevate(elevate)
-                .run()?;
+        // Resolve it before activation changes what it points to
+        let previous_system = match &self.target_host {
+            Some(_) => current_profile.clone(),
+            None => current_profile
+                .canonicalize()
+                .context("Resolving the current system")?,
+        };
+        let previous_generation = current_generation(&profile, self.target_host.as_deref())?;
+
+        hooks.run(Phase::PreActivate)?;
+
+        audit.installable(&self.common.installable);
+        audit.out_path = Some(toplevel_path.clone());
+        audit.start(audit::Scope::System);
+
+        if let Some(host) = &self.target_host {
+            if !dry_activate {
+                copy_to_host(host, &toplevel_path)?;
+            }
+        }
+
+        // Lets `nh os info` show where the generation came from
+        if let Some(git) = &git_state {
+            if let Err(err) = git.save(&toplevel_path, elevate, self.target_host.clone()) {
+                warn!("Failed to record the git revision: {err}");
+            }
         }
 
+        // Scheduled before activation in case the ssh session dies, with time for the health
+        // checks. The countdown starts over once they're done.
+        let mut pending = match self.confirm_within {
+            Some(within) => {
+                let checks_timeout = if health_checks.is_empty() {
+                    Duration::ZERO
+                } else {
+                    health_checks.timeout
+                };
+                let pending = PendingConfirmation {
+                    previous_system: previous_system.clone(),
+                    new_system: target_profile.clone(),
+                    deadline: Utc::now() + chrono::Duration::from_std(*within + checks_timeout)?,
+                    previous_generation: previous_generation.filter(|_| matches!(variant, Switch)),
+                };
+                pending.schedule(&profile, elevate, self.target_host.clone())?;
+                Some(pending)
+            }
+            None => None,
+        };
+
+        // Until the new configuration is active the profile hasn't moved, so the pending revert
+        // mustn't reset it later on its own. Once it's active, even with failed units, the
+        // revert has to stay in place
+        let units_failed = match variant {
+            // !! Use the target profile aka spec-namespaced
+            Test | Switch => {
+                match activate_test(&target_profile, elevate, self.target_host.clone()) {
+                    Ok(units_failed) => units_failed,
+                    Err(err) => {
+                        if pending.is_some() {
+                            if let Err(cancel_err) =
+                                confirm::cancel(elevate, self.target_host.clone())
+                            {
+                                warn!("Failed to cancel the pending revert: {cancel_err}");
+                            }
+                        }
+                        return Err(err);
+                    }
+                }
+            }
+            _ => None,
+        };
+
         if let Boot | Switch = variant {
-            Command::new("nix")
-                .elevate(elevate)
-                .args(["build", "--no-link", "--profile", SYSTEM_PROFILE])
-                .arg(out_path.get_path())
-                .run()?;
+            set_profile(&profile, &toplevel_path, elevate, self.target_host.clone())?;
+        }
 
-            // !! Use the base profile aka no spec-namespace
-            let switch_to_configuration = out_path
-                .get_path()
-                .join("bin")
-                .join("switch-to-configuration");
+        if matches!(variant, Test | Switch) && !health_checks.is_empty() {
+            if let Err(err) = health_checks.run(self.target_host.as_deref()) {
+                warn!(
+                    "Restoring the previous system {}",
+                    previous_system.display()
+                );
+                if matches!(variant, Switch) && previous_generation.is_none() {
+                    warn!(
+                        "{} had no generation before, it can't be reset",
+                        profile.display()
+                    );
+                }
+                restore_previous(
+                    &previous_system,
+                    previous_generation
+                        .filter(|_| matches!(variant, Switch))
+                        .map(|generation| (profile.as_path(), generation)),
+                    elevate,
+                    self.target_host.clone(),
+                )
+                .wrap_err("Failed to restore the previous system")?;
+                if pending.is_some() {
+                    confirm::cancel(elevate, self.target_host.clone())?;
+                }
+                return Err(err);
+            }
+        }
 
-            Command::new(switch_to_configuration)
-                .arg("boot")
-                .elevate(elevate)
-                .message("Adding configuration to bootloader")
-                .run()?;
+        if let (Some(pending), Some(within)) = (&mut pending, self.confirm_within) {
+            pending.rearm(*within, &profile, elevate, self.target_host.clone())?;
+        }
+
+        if let Some(pending) = pending {
+            if pending.wait_for_key() {
+                confirm::cancel(elevate, self.target_host.clone())?;
+                info!("Confirmed the new configuration");
+            } else if std::io::stdin().is_terminal() {
+                bail!("The new configuration wasn't confirmed in time, the previous one is being restored");
+            } else {
+                let confirm = match &self.target_host {
+                    Some(host) => format!("nh os confirm --target-host {host}"),
+                    None => String::from("nh os confirm"),
+                };
+                warn!(
+                    "Run `{confirm}` before {} to keep the new configuration",
+                    pending.deadline.with_timezone(&Local).format("%H:%M:%S")
+                );
+            }
+        }
+
+        // The new configuration stays, but the failed units are still reported
+        if let Some(err) = units_failed {
+            return Err(err.wrap_err("Some units failed to start during activation"));
+        }
+
+        if matches!(variant, Boot | Switch) && self.target_host.is_none() {
+            audit.generation = generations::current(&profile).ok();
         }
 
+        hooks.run(Phase::PostActivate)?;
+
+        let reboot_reasons = match variant {
+            Test | Switch => reboot::check(self.target_host.as_deref())?,
+            _ => Vec::new(),
+        };
+
         // Make sure out_path is not accidentally dropped
         // https://docs.rs/tempfile/3.12.0/tempfile/index.html#early-drop-pitfall
         drop(out_path);
 
+        if !reboot_reasons.is_empty() {
+            reboot::print(&reboot_reasons);
+
+            if self.reboot_if_needed {
+                reboot::reboot(elevate, self.target_host.clone())?;
+            } else if let Some(code) = self.reboot_exit_code {
+                return Ok(Some(code));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+/// Point the profile and the bootloader at the new configuration
+fn set_profile(
+    profile: &Path,
+    toplevel_path: &Path,
+    elevate: bool,
+    host: Option<String>,
+) -> Result<()> {
+    if profile.starts_with(generations::SYSTEM_PROFILES_DIR) {
+        Command::new("mkdir")
+            .args(["-p", "-m", "0755", generations::SYSTEM_PROFILES_DIR])
+            .elevate(elevate)
+            .ssh(host.clone())
+            .run()?;
+    }
+
+    Command::new("nix-env")
+        .elevate(elevate)
+        .arg("--profile")
+        .arg(profile)
+        .arg("--set")
+        .arg(toplevel_path)
+        .ssh(host.clone())
+        .run()?;
+
+    // !! Use the base profile aka no spec-namespace
+    let switch_to_configuration = toplevel_path.join("bin").join("switch-to-configuration");
+
+    Command::new(switch_to_configuration)
+        .arg("boot")
+        .elevate(elevate)
+        .ssh(host)
+        .message("Adding configuration to bootloader")
+        .run()
+}
+
+/// Run `switch-to-configuration test` of `target_profile`. Exit code 4 means some units failed,
+/// but the configuration is active anyway, so that error is returned as `Ok` for the profile
+/// and bootloader to still follow it
+fn activate_test(
+    target_profile: &Path,
+    elevate: bool,
+    host: Option<String>,
+) -> Result<Option<Report>> {
+    let activation = Command::new(target_profile.join("bin").join("switch-to-configuration"))
+        .arg("test")
+        .message("Activating configuration")
+        .elevate(elevate)
+        .ssh(host)
+        .run();
+
+    match activation {
+        Ok(()) => Ok(None),
+        Err(err) if commands::exit_code(&err) == Some(4) => Ok(Some(err)),
+        Err(err) => Err(err),
+    }
+}
+
+fn vm_script(vm: &Path) -> Result<PathBuf> {
+    fs::read_dir(vm.join("bin"))
+        .context("Reading the virtual machine's bin directory")?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .find(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with("run-") && name.ends_with("-vm"))
+        })
+        .ok_or_else(|| eyre!("No run-*-vm script found in {}", vm.display()))
+}
+
+/// Re-activate `previous` after a bad activation, also resetting the system profile to the
+/// given generation and the bootloader if they were changed
+fn restore_previous(
+    previous: &Path,
+    reset_profile: Option<(&Path, u64)>,
+    elevate: bool,
+    host: Option<String>,
+) -> Result<()> {
+    Command::new(previous.join("bin").join("switch-to-configuration"))
+        .arg("test")
+        .message("Re-activating the previous configuration")
+        .elevate(elevate)
+        .ssh(host.clone())
+        .run()?;
+
+    if let Some((profile, generation)) = reset_profile {
+        Command::new("nix-env")
+            .elevate(elevate)
+            .arg("--profile")
+            .arg(profile)
+            .arg("--switch-generation")
+            .arg(generation.to_string())
+            .ssh(host.clone())
+            .run()?;
+
+        Command::new(profile.join("bin").join("switch-to-configuration"))
+            .arg("boot")
+            .elevate(elevate)
+            .ssh(host)
+            .message("Restoring the previous bootloader entry")
+            .run()?;
+    }
+
+    Ok(())
+}
+
+fn copy_to_host(host: &str, toplevel: &Path) -> Result<()> {
+    Command::new("nix")
+        .args(["copy", "--to"])
+        .arg(format!("ssh://{host}"))
+        .arg(toplevel)
+        .message(format!("Copying configuration to {host}"))
+        .run()
+}
+
+fn remote_specialisation(host: &str) -> Result<Option<String>> {
+    let spec = Command::new("sh")
+        .arg("-c")
+        .arg(format!("cat {SPEC_LOCATION} 2>/dev/null || true"))
+        .ssh(Some(host.to_owned()))
+        .run_capture()?
+        .unwrap_or_default();
+
+    let spec = spec.trim();
+    Ok((!spec.is_empty()).then(|| spec.to_owned()))
+}
+
+/// Generation the profile points to on the local machine or `host`, None if it doesn't
+/// exist yet
+fn current_generation(profile: &Path, host: Option<&str>) -> Result<Option<u64>> {
+    let Some(host) = host else {
+        return profile
+            .is_symlink()
+            .then(|| generations::current(profile))
+            .transpose();
+    };
+
+    let target = Command::new("sh")
+        .args(["-c", r#"readlink "$1" || true"#, "sh"])
+        .arg(profile)
+        .ssh(Some(host.to_owned()))
+        .run_capture()?
+        .unwrap_or_default();
+
+    Ok(generations::from_dir(Path::new(target.trim())))
+}
+
+fn remote_current_system(host: &str) -> Result<PathBuf> {
+    let path = Command::new("readlink")
+        .args(["-f", CURRENT_PROFILE])
+        .ssh(Some(host.to_owned()))
+        .run_capture()?
+        .unwrap_or_default();
+
+    match path.trim() {
+        "" => bail!("Failed to resolve {CURRENT_PROFILE} on {host}"),
+        path => Ok(PathBuf::from(path)),
+    }
+}
+
+fn should_elevate(bypass_root_check: bool) -> Result<bool> {
+    if bypass_root_check {
+        warn!("Bypassing root check, now running nix as root");
+        Ok(false)
+    } else {
+        if nix::unistd::Uid::effective().is_root() {
+            bail!("Don't run nh os as root. I will call sudo internally as needed");
+        }
+        Ok(true)
+    }
+}
+
+impl OsConfirmArgs {
+    fn confirm(self) -> Result<()> {
+        let elevate = should_elevate(self.bypass_root_check)?;
+        confirm::confirm(elevate, self.target_host)
+    }
+}
+
+impl OsRollbackArgs {
+    fn rollback(self) -> Result<()> {
+        let elevate = should_elevate(self.bypass_root_check)?;
+
+        let profile = generations::system_profile(self.profile_name.as_deref())?;
+        let profile = profile.as_path();
+        let generations = generations::list(profile)?;
+        let current = generations::current(profile)?;
+        debug!(?current, ?generations);
+
+        let (target, target_path) = match self.to {
+            Some(number) => {
+                if number == current {
+                    bail!("Generation {number} is already the current one");
+                }
+                generations
+                    .into_iter()
+                    .find(|(n, _)| *n == number)
+                    .ok_or_else(|| eyre!("Generation {number} doesn't exist"))?
+            }
+            None => generations
+                .into_iter()
+                .rev()
+                .find(|(n, _)| *n < current)
+                .ok_or_else(|| eyre!("No generation older than {current} to rollback to"))?,
+        };
+
+        info!("Rolling back from generation {current} to {target}");
+
+        let current_specialisation = std::fs::read_to_string(SPEC_LOCATION).ok();
+        let target_profile = match &current_specialisation {
+            Some(spec) if target_path.join("specialisation").join(spec).exists() => {
+                target_path.join("specialisation").join(spec)
+            }
+            Some(spec) => {
+                warn!("Generation {target} doesn't have specialisation {spec}, using the base one");
+                target_path.clone()
+            }
+            None => target_path.clone(),
+        };
+
+        debug!(?target_profile);
+
+        let changes = diff::print_diff(Path::new(CURRENT_PROFILE), &target_profile)?;
+
+        if self.dry {
+            if self.ask {
+                warn!("--ask has no effect as dry run was requested");
+            }
+            return Ok(());
+        }
+
+        if self.ask {
+            info!("Rollback to generation {target}?");
+            let confirmation = dialoguer::Confirm::new().default(false).interact()?;
+
+            if !confirmation {
+                bail!("User rejected the rollback");
+            }
+        }
+
+        // Resolve it before activation changes what it points to
+        let previous_system = Path::new(CURRENT_PROFILE)
+            .canonicalize()
+            .context("Resolving the current system")?;
+
+        let mut audit = audit::Entry::new("os", "rollback");
+        audit.out_path = Some(target_path.clone());
+        audit.generation = Some(target);
+        audit.changes = Some(changes.summary());
+        audit.start(audit::Scope::System);
+
+        let result = switch_generation(
+            profile,
+            current,
+            target,
+            &target_path,
+            &target_profile,
+            &previous_system,
+            elevate,
+        );
+        audit.finish(&result);
+        result
+    }
+}
+
+/// Set the system profile to generation `target` and activate it, going back to `current`
+/// and re-activating `previous_system` if that fails
+fn switch_generation(
+    profile: &Path,
+    current: u64,
+    target: u64,
+    target_path: &Path,
+    target_profile: &Path,
+    previous_system: &Path,
+    elevate: bool,
+) -> Result<()> {
+    Command::new("nix-env")
+        .elevate(elevate)
+        .arg("--profile")
+        .arg(profile)
+        .arg("--switch-generation")
+        .arg(target.to_string())
+        .message(format!("Setting system profile to generation {target}"))
+        .run()?;
+
+    // The target never became active, so only the profile has to go back
+    let units_failed = match activate_test(target_profile, elevate, None) {
+        Ok(units_failed) => units_failed,
+        Err(err) => {
+            warn!("Activation failed, resetting system profile to generation {current}");
+            Command::new("nix-env")
+                .elevate(elevate)
+                .arg("--profile")
+                .arg(profile)
+                .arg("--switch-generation")
+                .arg(current.to_string())
+                .run()?;
+            return Err(err);
+        }
+    };
+
+    if let Err(err) = Command::new(target_path.join("bin").join("switch-to-configuration"))
+        .arg("boot")
+        .elevate(elevate)
+        .message("Adding configuration to bootloader")
+        .run()
+    {
+        warn!("Updating the bootloader failed, going back to generation {current}");
+        restore_previous(previous_system, Some((profile, current)), elevate, None)
+            .wrap_err("Failed to restore the previous system")?;
+        return Err(err);
+    }
+
+    match units_failed {
+        Some(err) => Err(err.wrap_err("Some units failed to start during activation")),
+        None => Ok(()),
+    }
+}
+
+impl OsSpecialisationArgs {
+    fn run(self) -> Result<()> {
+        let (base, active) = running_specialisation()?;
+        debug!(?base, ?active);
+
+        match self.subcommand {
+            OsSpecialisationSubcommand::List => {
+                use owo_colors::OwoColorize;
+
+                let marker = |is_active: bool| if is_active { " (current)" } else { "" };
+                println!("{}{}", "base".bold(), marker(active.is_none()));
+                for spec in generations::specialisations(&base) {
+                    let is_active = active.as_deref() == Some(spec.as_str());
+                    println!("{spec}{}", marker(is_active));
+                }
+            }
+            OsSpecialisationSubcommand::Switch(args) => {
+                let elevate = should_elevate(args.bypass_root_check)?;
+
+                let target = if args.name == "base" {
+                    base.clone()
+                } else {
+                    specialisation::path(&base, &args.name)?
+                };
+
+                diff::print_diff(Path::new(CURRENT_PROFILE), &target)?;
+
+                if args.dry {
+                    if args.ask {
+                        warn!("--ask has no effect as dry run was requested");
+                    }
+                    return Ok(());
+                }
+
+                if args.ask {
+                    info!("Switch to {}?", args.name);
+                    let confirmation = dialoguer::Confirm::new().default(false).interact()?;
+
+                    if !confirmation {
+                        bail!("User rejected the specialisation switch");
+                    }
+                }
+
+                Command::new(target.join("bin").join("switch-to-configuration"))
+                    .arg("test")
+                    .message(format!("Activating {}", args.name))
+                    .elevate(elevate)
+                    .run()?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Base toplevel of the running system, and the name of the active specialisation, if any
+fn running_specialisation() -> Result<(PathBuf, Option<String>)> {
+    let current = Path::new(CURRENT_PROFILE)
+        .canonicalize()
+        .context("Resolving the running system")?;
+
+    // A specialisation's toplevel doesn't link back to its base, so look for the running
+    // system among the specialisations of every generation in the system profiles
+    let mut profiles = vec![generations::system_profile(None)?];
+    if let Ok(entries) = fs::read_dir(generations::SYSTEM_PROFILES_DIR) {
+        profiles.extend(
+            entries
+                .filter_map(|entry| entry.ok().map(|e| e.path()))
+                .filter(|path| !path.to_string_lossy().ends_with("-link")),
+        );
+    }
+
+    for profile in &profiles {
+        for (_, generation) in generations::list(profile)?.into_iter().rev() {
+            for spec in generations::specialisations(&generation) {
+                let spec_path = generation.join("specialisation").join(&spec);
+                if spec_path.canonicalize().ok().as_ref() == Some(&current) {
+                    let base = generation
+                        .canonicalize()
+                        .context("Resolving the base system")?;
+                    return Ok((base, Some(spec)));
+                }
+            }
+        }
+    }
+
+    // A system activated with `nh os test` isn't in any profile, so its base can't be found
+    if let Ok(spec) = fs::read_to_string(SPEC_LOCATION) {
+        bail!(
+            "Specialisation {} is running, but its base system isn't in any profile",
+            spec.trim()
+        );
+    }
+
+    Ok((current, None))
+}
+
+impl OsDiffArgs {
+    fn diff(self) -> Result<()> {
+        let from = resolve_generation(&self.from, &self.profile)?;
+        let to = resolve_generation(&self.to, &self.profile)?;
+        debug!(?from, ?to);
+
+        diff::print_diff(&from, &to)?;
         Ok(())
     }
 }
 
-pub fn toplevel_for<S: AsRef<str>>(hostname: S, installable: Installable) -> Installable {
+/// Resolve a generation number, `current`, `booted` or a path into a system path
+fn resolve_generation(spec: &str, profile: &Path) -> Result<PathBuf> {
+    match spec {
+        "current" => Ok(PathBuf::from(CURRENT_PROFILE)),
+        "booted" => Ok(PathBuf::from(generations::BOOTED_SYSTEM)),
+        _ => {
+            if let Ok(number) = spec.parse::<u64>() {
+                return generations::list(profile)?
+                    .into_iter()
+                    .find(|(n, _)| *n == number)
+                    .map(|(_, path)| path)
+                    .ok_or_else(|| {
+                        eyre!("Generation {number} doesn't exist in {}", profile.display())
+                    });
+            }
+
+            let path = PathBuf::from(spec);
+            if !path.exists() {
+                bail!("{spec} is not a generation number, `current`, `booted` or an existing path");
+            }
+            Ok(path)
+        }
+    }
+}
+
+/// Select `config.system.build.<final_attr>` of the configuration for `hostname`
+pub fn toplevel_for<S: AsRef<str>>(
+    hostname: S,
+    installable: Installable,
+    final_attr: &str,
+) -> Installable {
     let mut res = installable.clone();
     let hostname = hostname.as_ref().to_owned();
 
-    let toplevel = ["config", "system", "build", "toplevel"]
+    let toplevel = ["config", "system", "build", final_attr]
         .into_iter()
         .map(String::from);
 
@@ -199,6 +952,9 @@ pub fn toplevel_for<S: AsRef<str>>(hostname: S, installable: Installable) -> Ins
         }
         Installable::Expression {
             ref mut attribute, ..
+        }
+        | Installable::Channel {
+            ref mut attribute, ..
         } => {
             attribute.extend(toplevel);
         }
@@ -210,7 +966,7 @@ pub fn toplevel_for<S: AsRef<str>>(hostname: S, installable: Installable) -> Ins
 
 impl OsReplArgs {
     fn run(self) -> Result<()> {
-        let mut target_installable = self.installable;
+        let target_installable = self.installable;
 
         if matches!(target_installable, Installable::Store { .. }) {
             bail!("Nix doesn't support nix store installables.");
@@ -220,20 +976,28 @@ impl OsReplArgs {
             .hostname
             .unwrap_or_else(|| hostname::get().unwrap().to_str().unwrap().to_string());
 
-        if let Installable::Flake {
-            ref mut attribute, ..
-        } = target_installable
-        {
-            if attribute.is_empty() {
-                attribute.push(String::from("nixosConfigurations"));
-                attribute.push(hostname);
-            }
-        }
+        hosts::with_configuration(
+            &target_installable,
+            "nixosConfigurations",
+            hostname,
+            |hostname| {
+                let mut installable = target_installable.clone();
+                if let Installable::Flake {
+                    ref mut attribute, ..
+                } = installable
+                {
+                    if attribute.is_empty() {
+                        attribute.push(String::from("nixosConfigurations"));
+                        attribute.push(hostname.to_owned());
+                    }
+                }
 
-        Command::new("nix")
-            .arg("repl")
-            .args(target_installable.to_args())
-            .run()?;
+                Command::new("nix")
+                    .arg("repl")
+                    .args(installable.to_args())
+                    .run()
+            },
+        )?;
 
         Ok(())
     }
@@ -241,9 +1005,10 @@ impl OsReplArgs {
 
 impl OsGenerationsArgs {
     fn info(&self) -> Result<()> {
-        let profile = match self.profile {
-            Some(ref p) => PathBuf::from(p),
-            None => bail!("Profile path is required"),
+        let profile = match (&self.profile_name, &self.profile) {
+            (Some(name), _) => generations::system_profile(Some(name))?,
+            (None, Some(p)) => PathBuf::from(p),
+            (None, None) => bail!("Profile path is required"),
         };
 
         if !profile.is_symlink() {
@@ -253,31 +1018,45 @@ impl OsGenerationsArgs {
             ));
         }
 
-        let profile_dir = profile.parent().unwrap_or_else(|| Path::new("."));
-
-        let generations: Vec<_> = fs::read_dir(profile_dir)?
-            .filter_map(|entry| {
-                entry.ok().and_then(|e| {
-                    let path = e.path();
-                    if path
-                        .file_name()?
-                        .to_str()?
-                        .starts_with(profile.file_name()?.to_str()?)
-                    {
-                        Some(path)
-                    } else {
-                        None
-                    }
-                })
-            })
-            .collect();
-
-        let descriptions: Vec<generations::GenerationInfo> = generations
+        let generation_dirs = generations::list(&profile)?;
+        let mut descriptions: Vec<generations::GenerationInfo> = generation_dirs
             .iter()
-            .filter_map(|gen_dir| generations::describe(gen_dir, &profile))
+            .filter_map(|(_, gen_dir)| generations::describe(gen_dir, &profile))
             .collect();
 
-        generations::print_info(descriptions);
+        let mut profile_pins = pins::read(&profile)?;
+        for description in descriptions.iter_mut() {
+            description.pin = profile_pins.remove(&description.number);
+        }
+
+        let jobs = std::thread::available_parallelism().map_or(4, |n| n.get());
+        let sizes: BTreeMap<u64, u64> = parallel_map(&generation_dirs, jobs, |(number, dir)| {
+            diff::closure_size(dir).ok().map(|size| (*number, size))
+        })
+        .into_iter()
+        .flatten()
+        .collect();
+
+        for description in descriptions.iter_mut() {
+            description.closure_size = sizes.get(&description.number).copied();
+        }
+
+        let format = if self.json {
+            InfoFormat::Json
+        } else {
+            self.format
+        };
+
+        match format {
+            InfoFormat::Table => {
+                generations::print_info(&descriptions);
+                if self.sparkline {
+                    generations::print_sparkline(&descriptions);
+                }
+            }
+            InfoFormat::Json => generations::print_json(&descriptions)?,
+            InfoFormat::Csv => generations::print_csv(&descriptions),
+        }
 
         Ok(())
     }
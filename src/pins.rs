@@ -0,0 +1,66 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::{Context, ContextCompat};
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::Result;
+
+// Pins live next to the profiles they refer to, so that system profiles use
+// /nix/var/nix/profiles/.nh-pins and user profiles use their XDG state directory
+const PINS_FILE: &str = ".nh-pins";
+
+/// A generation that nh clean must never remove
+#[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Pin {
+    pub label: Option<String>,
+}
+
+/// Pinned generations, by profile name and generation number
+type PinsFile = BTreeMap<String, BTreeMap<u64, Pin>>;
+
+fn pins_file(profile: &Path) -> Result<(PathBuf, String)> {
+    let name = profile
+        .file_name()
+        .and_then(|name| name.to_str())
+        .context("Checking profile's name")?
+        .to_owned();
+    let dir = profile.parent().unwrap_or_else(|| Path::new("."));
+
+    Ok((dir.join(PINS_FILE), name))
+}
+
+fn read_file(path: &Path) -> Result<PinsFile> {
+    match fs::read_to_string(path) {
+        Ok(contents) => serde_json::from_str(&contents)
+            .with_context(|| format!("Parsing pins file {}", path.display())),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(PinsFile::new()),
+        Err(err) => Err(err).with_context(|| format!("Reading pins file {}", path.display())),
+    }
+}
+
+/// Pinned generations of a profile
+pub fn read(profile: &Path) -> Result<BTreeMap<u64, Pin>> {
+    let (path, name) = pins_file(profile)?;
+    let mut pins = read_file(&path)?;
+    Ok(pins.remove(&name).unwrap_or_default())
+}
+
+/// Replace the pinned generations of a profile
+pub fn write(profile: &Path, profile_pins: BTreeMap<u64, Pin>) -> Result<()> {
+    let (path, name) = pins_file(profile)?;
+    let mut pins = read_file(&path)?;
+
+    if profile_pins.is_empty() {
+        pins.remove(&name);
+    } else {
+        pins.insert(name, profile_pins);
+    }
+
+    debug!(?path, ?pins, "Writing pins");
+
+    fs::write(&path, serde_json::to_string_pretty(&pins)?)
+        .with_context(|| format!("Writing pins file {}", path.display()))
+}
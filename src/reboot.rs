@@ -0,0 +1,134 @@
+use std::path::{Path, PathBuf};
+
+use owo_colors::OwoColorize;
+use tracing::{debug, warn};
+
+use crate::commands::Command;
+use crate::generations;
+use crate::Result;
+
+const CURRENT_SYSTEM: &str = "/run/current-system";
+
+/// Parts of a system that only take effect after a reboot
+const COMPONENTS: [&str; 4] = ["kernel", "initrd", "kernel-modules", "systemd"];
+
+/// Reasons why the current system differs from the booted one in a way that needs a reboot
+pub fn check(host: Option<&str>) -> Result<Vec<String>> {
+    let (booted, current) = match host {
+        Some(host) => (
+            remote_components(host, generations::BOOTED_SYSTEM)?,
+            remote_components(host, CURRENT_SYSTEM)?,
+        ),
+        None => (
+            components(Path::new(generations::BOOTED_SYSTEM)),
+            components(Path::new(CURRENT_SYSTEM)),
+        ),
+    };
+
+    debug!(?booted, ?current);
+
+    // Containers and some WSL setups have no booted system to compare with
+    if booted.iter().all(Option::is_none) {
+        warn!("Couldn't resolve the booted system, unable to tell whether a reboot is needed");
+        return Ok(Vec::new());
+    }
+
+    Ok(reasons(&booted, &current))
+}
+
+pub fn print(reasons: &[String]) {
+    warn!("{}", "Reboot required".bold());
+    for reason in reasons {
+        println!("{} {reason}", "[B]".yellow());
+    }
+}
+
+/// Reboot the target host, locally or through ssh
+pub fn reboot(elevate: bool, host: Option<String>) -> Result<()> {
+    Command::new("systemctl")
+        .arg("reboot")
+        .elevate(elevate)
+        .ssh(host)
+        .message("Rebooting")
+        .run()
+}
+
+fn components(system: &Path) -> Vec<Option<PathBuf>> {
+    COMPONENTS
+        .iter()
+        .map(|component| system.join(component).canonicalize().ok())
+        .collect()
+}
+
+fn remote_components(host: &str, system: &str) -> Result<Vec<Option<PathBuf>>> {
+    // One line per component, empty if it doesn't exist
+    let script = COMPONENTS
+        .iter()
+        .map(|component| format!("readlink -e {system}/{component} || echo"))
+        .collect::<Vec<_>>()
+        .join("; ");
+
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(script)
+        .ssh(Some(host.to_owned()))
+        .run_capture()?
+        .unwrap_or_default();
+
+    let mut lines = output.lines().map(str::trim);
+    Ok(COMPONENTS
+        .iter()
+        .map(|_| {
+            lines
+                .next()
+                .filter(|line| !line.is_empty())
+                .map(PathBuf::from)
+        })
+        .collect())
+}
+
+fn reasons(booted: &[Option<PathBuf>], current: &[Option<PathBuf>]) -> Vec<String> {
+    COMPONENTS
+        .iter()
+        .zip(booted.iter().zip(current))
+        .filter(|(_, (booted, current))| booted != current)
+        .map(|(component, (booted, current))| {
+            let versions = booted
+                .as_deref()
+                .zip(current.as_deref())
+                .filter(|_| *component == "kernel")
+                .and_then(|(booted, current)| {
+                    Some((
+                        generations::kernel_version(booted)?,
+                        generations::kernel_version(current)?,
+                    ))
+                });
+
+            match versions {
+                Some((booted, current)) if booted != current => {
+                    format!("{component} changed ({booted} -> {current})")
+                }
+                _ => format!("{component} changed"),
+            }
+        })
+        .collect()
+}
+
+#[test]
+fn test_reasons() {
+    let booted = [
+        Some(PathBuf::from("/nix/store/a-linux/bzImage")),
+        Some(PathBuf::from("/nix/store/b-initrd/initrd")),
+        Some(PathBuf::from("/nix/store/c-modules")),
+        Some(PathBuf::from("/nix/store/d-systemd")),
+    ];
+    let mut current = booted.clone();
+    assert!(reasons(&booted, &current).is_empty());
+
+    current[1] = Some(PathBuf::from("/nix/store/e-initrd/initrd"));
+    current[3] = None;
+    assert_eq!(
+        reasons(&booted, &current),
+        ["initrd changed", "systemd changed"]
+    );
+}
@@ -0,0 +1,75 @@
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::bail;
+use tracing::debug;
+
+use crate::generations;
+use crate::Result;
+
+/// Choose the specialisation of `toplevel` to activate.
+///
+/// An explicit `--specialisation` wins over the currently running one, and the chosen
+/// name must exist in the built configuration.
+pub fn resolve(
+    toplevel: &Path,
+    explicit: Option<&str>,
+    current: Option<&str>,
+    no_specialisation: bool,
+) -> Result<Option<String>> {
+    if no_specialisation {
+        return Ok(None);
+    }
+
+    let current = current.map(str::trim).filter(|s| !s.is_empty());
+    debug!(?explicit, ?current);
+
+    match explicit.or(current) {
+        Some(name) => {
+            path(toplevel, name)?;
+            Ok(Some(name.to_owned()))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Toplevel of the specialisation `name` of `toplevel`, which must exist
+pub fn path(toplevel: &Path, name: &str) -> Result<PathBuf> {
+    let path = toplevel.join("specialisation").join(name);
+    if path.exists() {
+        return Ok(path);
+    }
+
+    let available = generations::specialisations(toplevel);
+    if available.is_empty() {
+        bail!("Specialisation `{name}` doesn't exist, the configuration has no specialisations");
+    }
+
+    let suggestion = closest_match(name, &available)
+        .map(|closest| format!(", did you mean `{closest}`?"))
+        .unwrap_or_else(|| ".".to_owned());
+
+    bail!(
+        "Specialisation `{name}` doesn't exist{suggestion} Available specialisations: {}",
+        available.join(", ")
+    );
+}
+
+fn closest_match<'a>(name: &str, candidates: &'a [String]) -> Option<&'a str> {
+    let max_distance = (name.len() / 3).max(2);
+
+    candidates
+        .iter()
+        .map(|candidate| (strsim::levenshtein(name, candidate), candidate))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate.as_str())
+}
+
+#[test]
+fn test_closest_match() {
+    let candidates = ["docked", "travel", "gaming"].map(String::from);
+
+    assert_eq!(closest_match("dokced", &candidates), Some("docked"));
+    assert_eq!(closest_match("travl", &candidates), Some("travel"));
+    assert_eq!(closest_match("office", &candidates), None);
+}
@@ -17,6 +17,17 @@ pub fn update(installable: &Installable, input: Option<String>) -> Result<()> {
 
             cmd.arg("--flake").arg(reference).run()?;
         }
+        Installable::Channel { .. } => {
+            let mut cmd = Command::new("nix-channel").arg("--update").elevate(true);
+
+            if let Some(i) = input {
+                cmd = cmd.arg(&i).message(format!("Updating channel {}", i));
+            } else {
+                cmd = cmd.message("Updating all channels");
+            }
+
+            cmd.run()?;
+        }
         _ => {
             warn!(
                 "Only flake installables can be updated, {} is not supported",
@@ -3,6 +3,7 @@ extern crate semver;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::str;
+use std::sync::Mutex;
 
 use color_eyre::{eyre, Result};
 use semver::Version;
@@ -22,6 +23,7 @@ use tempfile::TempDir;
 /// # Returns
 ///
 /// * `Result<std::cmp::Ordering>` - The comparison result.
+#[allow(dead_code)]
 pub fn compare_semver(current: &str, target: &str) -> Result<std::cmp::Ordering> {
     let current = Version::parse(current)?;
     let target = Version::parse(target)?;
@@ -37,6 +39,7 @@ pub fn compare_semver(current: &str, target: &str) -> Result<std::cmp::Ordering>
 /// # Returns
 ///
 /// * `Result<String>` - The Nix version string or an error if the version cannot be retrieved.
+#[allow(dead_code)]
 pub fn get_nix_version() -> Result<String> {
     let output = Command::new("nix").arg("--version").output()?;
 
@@ -74,3 +77,71 @@ impl MaybeTempPath for (PathBuf, TempDir) {
         self.0.as_ref()
     }
 }
+
+/// Formats a size in bytes using binary units, like `1.5 GiB`.
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{bytes} {}", UNITS[0])
+    } else {
+        format!("{size:.1} {}", UNITS[unit])
+    }
+}
+
+/// Formats a change in size with its sign, like `+1.5 GiB`.
+pub fn format_size_delta(delta: i64) -> String {
+    let sign = if delta < 0 { "-" } else { "+" };
+    format!("{sign}{}", format_size(delta.unsigned_abs()))
+}
+
+/// Maps `f` over `items` on up to `jobs` threads, keeping the order of the items.
+pub fn parallel_map<T, R, F>(items: &[T], jobs: usize, f: F) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> R + Sync,
+{
+    let queue = Mutex::new(items.iter().enumerate());
+    let results = Mutex::new(Vec::with_capacity(items.len()));
+
+    std::thread::scope(|scope| {
+        for _ in 0..jobs.clamp(1, items.len().max(1)) {
+            scope.spawn(|| loop {
+                let Some((index, item)) = queue.lock().unwrap().next() else {
+                    break;
+                };
+                let result = f(item);
+                results.lock().unwrap().push((index, result));
+            });
+        }
+    });
+
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|(index, _)| *index);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+#[test]
+fn test_parallel_map() {
+    let items: Vec<u64> = (0..20).collect();
+    assert_eq!(
+        parallel_map(&items, 4, |n| n * 2),
+        items.iter().map(|n| n * 2).collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn test_format_size() {
+    assert_eq!(format_size(0), "0 B");
+    assert_eq!(format_size(1023), "1023 B");
+    assert_eq!(format_size(1536), "1.5 KiB");
+    assert_eq!(format_size(3 * 1024 * 1024 * 1024), "3.0 GiB");
+}